@@ -0,0 +1,116 @@
+use crate::churn::detect_parent_changes;
+use crate::clock::align_clocks;
+use crate::counters::create_delta_views;
+use crate::deliveries::correlate_deliveries;
+use crate::error::Result;
+use crate::latency::summarize_latencies;
+use crate::migrations;
+use crate::reboots::detect_reboots;
+use crate::topology::build_topology;
+use rusqlite::Connection;
+
+/// Options of the analysis run on the experiments once they are ingested
+#[derive(Debug, Clone, Default)]
+pub struct AnalysisOptions {
+    /// Estimate the drift of the server clock, and not only its offset
+    pub drift: bool,
+}
+
+pub(crate) fn table_exists(connection: &Connection, table: &str) -> Result<bool> {
+    let count: i64 = connection.query_row(
+        "SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = ?",
+        [table],
+        |row| row.get(0),
+    )?;
+    Ok(count > 0)
+}
+
+/// A value read from the database, as text
+pub(crate) fn value_text(value: rusqlite::types::Value) -> String {
+    use rusqlite::types::Value;
+    match value {
+        Value::Null => String::new(),
+        Value::Integer(v) => v.to_string(),
+        Value::Real(v) => v.to_string(),
+        Value::Text(v) => v,
+        Value::Blob(v) => String::from_utf8_lossy(&v).to_string(),
+    }
+}
+
+/// Computes the derived tables of an experiment from its records
+pub fn analyze(
+    connection: &Connection,
+    experiment_id: i64,
+    options: &AnalysisOptions,
+) -> Result<()> {
+    connection.execute_batch("BEGIN")?;
+    let result = (|| {
+        detect_reboots(connection, experiment_id)?;
+        /* The deltas are computed within each boot */
+        create_delta_views(connection)?;
+        /* The deliveries use the aligned server timestamps */
+        align_clocks(connection, experiment_id, options.drift)?;
+        correlate_deliveries(connection, experiment_id)?;
+        summarize_latencies(connection, experiment_id)?;
+        /* The parents are named after the source addresses of the deliveries */
+        build_topology(connection, experiment_id)?;
+        detect_parent_changes(connection, experiment_id)
+    })();
+    match result {
+        Ok(()) => connection.execute_batch("COMMIT")?,
+        Err(_) => connection.execute_batch("ROLLBACK")?,
+    }
+    result
+}
+
+/// Opens the database at `path`, upgrading it to the latest version
+pub fn open_database(path: &str) -> Result<Connection> {
+    let connection = Connection::open(path)?;
+    migrations::migrate(&connection)?;
+    Ok(connection)
+}
+
+/// The ids and names of the experiments named in `names`, or of all of them
+/// when `names` is empty
+pub(crate) fn select_experiments(
+    connection: &Connection,
+    names: &[String],
+) -> Result<Vec<(i64, String)>> {
+    let experiments: Vec<(i64, String)> = connection
+        .prepare(r#"SELECT "id", "Name" FROM "experiments" ORDER BY "id""#)?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    for name in names {
+        if !experiments.iter().any(|(_, n)| n == name) {
+            eprintln!("Warning: no experiment named {}", name);
+        }
+    }
+    Ok(experiments
+        .into_iter()
+        .filter(|(_, name)| names.is_empty() || names.contains(name))
+        .collect())
+}
+
+/// The outcome of the analysis of each experiment, in the order of their ids
+pub type AnalysisOutcomes = Vec<(String, Result<()>)>;
+
+/// Analyzes the experiments of the database at `path` named in `names`, or
+/// all of them when `names` is empty.
+///
+/// Each experiment is analyzed in its own transaction, so a failing one does
+/// not prevent the others from being analyzed.
+pub fn analyze_database(
+    path: &str,
+    names: &[String],
+    options: &AnalysisOptions,
+) -> Result<AnalysisOutcomes> {
+    let connection = open_database(path)?;
+    Ok(select_experiments(&connection, names)?
+        .into_iter()
+        .map(|(id, name)| {
+            println!("Analyzing experiment {}", name);
+            let result = analyze(&connection, id, options);
+            (name, result)
+        })
+        .collect())
+}
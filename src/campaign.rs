@@ -0,0 +1,166 @@
+use crate::error::{Error, Result};
+use crate::record::Experiment;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// An experiment directory of the campaign which cannot be ingested
+#[derive(Debug, Clone, PartialEq)]
+pub struct Skipped {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+/// The experiments of a campaign, and the directories which were skipped
+#[derive(Debug, Default)]
+pub struct Campaign {
+    pub experiments: Vec<Experiment>,
+    pub skipped: Vec<Skipped>,
+}
+
+impl Campaign {
+    /// Walks the campaign rooted at `root`.
+    ///
+    /// Every directory holding `.zst` files is an experiment, named after its
+    /// path relative to `root`: its serial file is the one whose name
+    /// contains `serial`, and its server file the one whose name contains
+    /// `server`. Directories where either is missing or ambiguous are
+    /// skipped.
+    pub fn discover(root: &Path) -> Result<Campaign> {
+        let mut campaign = Campaign::default();
+        campaign.walk(root, root)?;
+        Ok(campaign)
+    }
+
+    fn walk(&mut self, root: &Path, directory: &Path) -> Result<()> {
+        let in_directory = |e: std::io::Error| Error::from(e).in_file(&directory.to_string_lossy());
+        let mut entries: Vec<PathBuf> = fs::read_dir(directory)
+            .map_err(in_directory)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<std::io::Result<_>>()
+            .map_err(in_directory)?;
+        entries.sort();
+
+        let archives: Vec<&PathBuf> = entries
+            .iter()
+            .filter(|p| p.is_file() && p.extension().is_some_and(|e| e == "zst"))
+            .collect();
+        if !archives.is_empty() {
+            self.add(root, directory, &archives);
+        }
+        for subdirectory in entries.iter().filter(|p| p.is_dir()) {
+            self.walk(root, subdirectory)?;
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, root: &Path, directory: &Path, archives: &[&PathBuf]) {
+        let find = |kind: &str| -> std::result::Result<String, String> {
+            let candidates: Vec<&&PathBuf> = archives
+                .iter()
+                .filter(|p| {
+                    p.file_name()
+                        .is_some_and(|n| n.to_string_lossy().contains(kind))
+                })
+                .collect();
+            match candidates.as_slice() {
+                [file] => Ok(file.to_string_lossy().to_string()),
+                [] => Err(format!("no {} file", kind)),
+                _ => Err(format!("{} {} files", candidates.len(), kind)),
+            }
+        };
+        match (find("serial"), find("server")) {
+            (Ok(serial), Ok(server)) => {
+                let name = match directory.strip_prefix(root) {
+                    Ok(relative) if relative != Path::new("") => relative.to_string_lossy(),
+                    _ => directory
+                        .file_name()
+                        .map_or(directory.to_string_lossy(), |n| n.to_string_lossy()),
+                };
+                self.experiments
+                    .push(Experiment::new(&name, &serial, &server));
+            }
+            (serial, server) => {
+                let reason = [serial.err(), server.err()]
+                    .iter()
+                    .flatten()
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                self.skipped.push(Skipped {
+                    path: directory.to_path_buf(),
+                    reason,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn experiments_are_discovered() {
+        let root = std::env::temp_dir().join(format!("moulinette-campaign-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let files = [
+            "a/run-1/serial.zst",
+            "a/run-1/server.zst",
+            "a/run-1/notes.txt",
+            "a/run-2/serial.zst",
+            "b/serial.zst",
+            "b/server-1.zst",
+            "b/server-2.zst",
+            "c/d/m3-serial.log.zst",
+            "c/d/server.log.zst",
+            "empty/notes.txt",
+        ];
+        for file in &files {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, b"").unwrap();
+        }
+
+        let campaign = Campaign::discover(&root).unwrap();
+        let experiments: Vec<(PathBuf, PathBuf, PathBuf)> = campaign
+            .experiments
+            .iter()
+            .map(|e| {
+                (
+                    PathBuf::from(&e.name),
+                    PathBuf::from(&e.serial_file),
+                    PathBuf::from(&e.server_file),
+                )
+            })
+            .collect();
+        assert_eq!(
+            experiments,
+            [
+                (
+                    PathBuf::from("a/run-1"),
+                    root.join("a/run-1/serial.zst"),
+                    root.join("a/run-1/server.zst"),
+                ),
+                (
+                    PathBuf::from("c/d"),
+                    root.join("c/d/m3-serial.log.zst"),
+                    root.join("c/d/server.log.zst"),
+                ),
+            ]
+        );
+        assert_eq!(
+            campaign.skipped,
+            [
+                Skipped {
+                    path: root.join("a/run-2"),
+                    reason: "no server file".to_string(),
+                },
+                Skipped {
+                    path: root.join("b"),
+                    reason: "2 server files".to_string(),
+                },
+            ]
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,163 @@
+use crate::error::Result;
+use crate::topology::{parent_runs, ParentRun};
+use rusqlite::{params, Connection};
+use std::collections::BTreeMap;
+
+const HOUR_NS: f64 = 3_600_000_000_000.0;
+
+/// Writes the parent changes of the nodes of the experiment to
+/// `parent_changes`, and their churn to `churn_summary` (changes per hour of
+/// observation) and `parent_durations` (time spent with each parent).
+///
+/// A change is dated by the first sample showing the new parent; the old
+/// rank is the last one advertised by the old parent, and the new rank the
+/// first one of the new parent. A node is observed from its first sample of
+/// `rpl_stats_parent` to its last one, which ends the time spent with its
+/// last parent.
+pub fn detect_parent_changes(connection: &Connection, experiment_id: i64) -> Result<()> {
+    for table in &["parent_changes", "churn_summary", "parent_durations"] {
+        connection.execute(
+            &format!(r#"DELETE FROM "{}" WHERE "experiment_id" = ?"#, table),
+            [experiment_id],
+        )?;
+    }
+    let runs = match parent_runs(connection, experiment_id)? {
+        Some(runs) => runs,
+        None => return Ok(()),
+    };
+
+    let mut insert_change = connection.prepare(
+        r#"INSERT INTO "parent_changes"
+           ("experiment_id", "node", "instance", "ts_ns", "old_parent", "new_parent",
+            "old_parent_address", "new_parent_address", "old_rank", "new_rank")
+           VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"#,
+    )?;
+    let mut changes = 0;
+    for pair in runs.windows(2) {
+        let (old, new) = (&pair[0], &pair[1]);
+        if old.child != new.child || old.instance != new.instance {
+            continue;
+        }
+        insert_change.execute(params![
+            experiment_id,
+            new.child,
+            new.instance,
+            new.from,
+            old.parent,
+            new.parent,
+            old.address,
+            new.address,
+            old.last_rank,
+            new.first_rank
+        ])?;
+        changes += 1;
+    }
+
+    let mut insert_summary = connection.prepare(
+        r#"INSERT INTO "churn_summary"
+           ("experiment_id", "node", "changes", "observed_ns", "changes_per_hour")
+           VALUES (?, ?, ?, ?, ?)"#,
+    )?;
+    let mut insert_duration = connection.prepare(
+        r#"INSERT INTO "parent_durations"
+           ("experiment_id", "node", "parent", "parent_address", "duration_ns", "share")
+           VALUES (?, ?, ?, ?, ?, ?)"#,
+    )?;
+    let mut nodes: BTreeMap<&str, Vec<&ParentRun>> = BTreeMap::new();
+    for run in &runs {
+        nodes.entry(&run.child).or_default().push(run);
+    }
+    for (node, runs) in nodes {
+        /* Every instance counts, but the first run of each is no change */
+        let instances = runs
+            .windows(2)
+            .filter(|pair| pair[0].instance != pair[1].instance)
+            .count()
+            + 1;
+        let changes = runs.len() - instances;
+        let first = runs.iter().map(|run| run.from).min().unwrap_or(0);
+        let last = runs.iter().map(|run| run.last).max().unwrap_or(0);
+        let observed = last - first;
+        let per_hour = if observed > 0 {
+            Some(changes as f64 / (observed as f64 / HOUR_NS))
+        } else {
+            None
+        };
+        insert_summary.execute(params![
+            experiment_id,
+            node,
+            changes as i64,
+            observed,
+            per_hour
+        ])?;
+
+        let mut durations: BTreeMap<(Option<&str>, Option<&str>), i64> = BTreeMap::new();
+        for run in &runs {
+            *durations
+                .entry((run.parent.as_deref(), run.address.as_deref()))
+                .or_default() += run.to.unwrap_or(run.last) - run.from;
+        }
+        let total: i64 = durations.values().sum();
+        for ((parent, address), duration) in durations {
+            let share = if total > 0 {
+                Some(duration as f64 / total as f64)
+            } else {
+                None
+            };
+            insert_duration.execute(params![
+                experiment_id,
+                node,
+                parent,
+                address,
+                duration,
+                share
+            ])?;
+        }
+    }
+    println!("{} parent changes", changes);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::open_database;
+
+    #[test]
+    fn the_last_parent_lasts_until_the_last_sample() {
+        let connection = open_database(":memory:").unwrap();
+        connection
+            .execute_batch(
+                r#"CREATE TABLE "rpl_stats_parent" (
+                     "experiment_id" INTEGER, "Node" TEXT, "Instance ID" TEXT,
+                     "IPv6 Adress" TEXT, "Rank" INTEGER, "ts_ns" INTEGER);
+                   INSERT INTO "rpl_stats_parent" VALUES
+                     (1, 'm3-2', '0', 'fe80::1', 256, 0), (1, 'm3-2', '0', 'fe80::3', 512, 10),
+                     (1, 'm3-2', '0', 'fe80::3', 384, 40);"#,
+            )
+            .unwrap();
+        detect_parent_changes(&connection, 1).unwrap();
+        let (changes, observed): (i64, i64) = connection
+            .query_row(
+                r#"SELECT "changes", "observed_ns" FROM "churn_summary""#,
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((changes, observed), (1, 40));
+        let durations: Vec<(String, i64)> = connection
+            .prepare(
+                r#"SELECT "parent_address", "duration_ns" FROM "parent_durations"
+                   ORDER BY "parent_address""#,
+            )
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            durations,
+            [("fe80::1".to_string(), 10), ("fe80::3".to_string(), 30)]
+        );
+    }
+}
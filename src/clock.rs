@@ -0,0 +1,280 @@
+use crate::analysis::table_exists;
+use crate::deliveries::SAME_PACKET;
+use crate::error::Result;
+use rusqlite::{params, Connection};
+use std::collections::BTreeMap;
+
+/// Width of the windows of send times in which the smallest delay is kept
+const WINDOW_NS: i64 = 60_000_000_000;
+
+/// The relation between the clock of the serial aggregator and the clock of
+/// the server, fitted on the lower envelope of the delays of the packets:
+///
+/// `server = serial + offset + drift * (serial - reference)`
+///
+/// One-way delays cannot tell the offset from the smallest latency, so the
+/// fastest packets get an aligned latency close to zero.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClockFit {
+    pub offset_ns: i64,
+    /// Drift of the server clock, in parts per million
+    pub drift_ppm: f64,
+    pub reference_ts_ns: i64,
+    pub pairs: usize,
+    pub windows: usize,
+    /// Root mean square distance from the window minima to the fit
+    pub residual_ns: f64,
+}
+
+impl ClockFit {
+    /// Fits the clocks on `(send, receive)` timestamp pairs, estimating the
+    /// drift when `drift` is set and the pairs span at least two windows
+    pub fn estimate(pairs: &[(i64, i64)], drift: bool) -> Option<ClockFit> {
+        let reference = pairs.iter().map(|(send, _)| *send).min()?;
+
+        /* Keep the fastest packet of each window */
+        let mut windows: BTreeMap<i64, (i64, i64)> = BTreeMap::new();
+        for &(send, receive) in pairs {
+            let delay = receive - send;
+            let minimum = windows
+                .entry((send - reference) / WINDOW_NS)
+                .or_insert((send, delay));
+            if delay < minimum.1 {
+                *minimum = (send, delay);
+            }
+        }
+        let minima: Vec<(i64, i64)> = windows.into_values().collect();
+
+        /* Least squares slope of the minima, in nanoseconds per nanosecond */
+        let slope = if drift && minima.len() >= 2 {
+            let n = minima.len() as f64;
+            let x: Vec<f64> = minima.iter().map(|(s, _)| (s - reference) as f64).collect();
+            let y: Vec<f64> = minima.iter().map(|(_, d)| *d as f64).collect();
+            let (mean_x, mean_y) = (x.iter().sum::<f64>() / n, y.iter().sum::<f64>() / n);
+            let covariance: f64 = x
+                .iter()
+                .zip(&y)
+                .map(|(x, y)| (x - mean_x) * (y - mean_y))
+                .sum();
+            let variance: f64 = x.iter().map(|x| (x - mean_x).powi(2)).sum();
+            if variance > 0.0 {
+                covariance / variance
+            } else {
+                0.0
+            }
+        } else {
+            0.0
+        };
+
+        /* Lower the line so that it lies below every minimum */
+        let trend = |send: i64| (slope * (send - reference) as f64).round() as i64;
+        let offset = minima.iter().map(|(s, d)| d - trend(*s)).min()?;
+        let residual = (minima
+            .iter()
+            .map(|(s, d)| ((d - offset - trend(*s)) as f64).powi(2))
+            .sum::<f64>()
+            / minima.len() as f64)
+            .sqrt();
+
+        Some(ClockFit {
+            offset_ns: offset,
+            drift_ppm: slope * 1e6,
+            reference_ts_ns: reference,
+            pairs: pairs.len(),
+            windows: minima.len(),
+            residual_ns: residual,
+        })
+    }
+
+    /// Converts a timestamp of the server clock to the serial aggregator clock
+    pub fn align(&self, server_ts_ns: i64) -> i64 {
+        let drift = self.drift_ppm / 1e6;
+        let elapsed = (server_ts_ns - self.offset_ns - self.reference_ts_ns) as f64;
+        server_ts_ns - self.offset_ns - (drift * elapsed / (1.0 + drift)).round() as i64
+    }
+}
+
+/// The send and first receive timestamps of the udp packets of the
+/// experiment, matched as in the deliveries
+fn packet_pairs(connection: &Connection, experiment_id: i64) -> Result<Vec<(i64, i64)>> {
+    let pairs = connection
+        .prepare(&format!(
+            r#"SELECT u."ts_ns", MIN(s."ts_ns")
+               FROM "udp" u JOIN "server" s
+                 ON s."experiment_id" = u."experiment_id" AND {}
+               WHERE u."experiment_id" = ? AND u."ts_ns" IS NOT NULL AND s."ts_ns" IS NOT NULL
+               GROUP BY u.rowid"#,
+            SAME_PACKET
+        ))?
+        .query_map([experiment_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(pairs)
+}
+
+/// Fits the server clock of the experiment on its udp packets, stores the
+/// fit in `clock_fit` and the aligned server timestamps in `aligned_ts_ns`
+pub fn align_clocks(
+    connection: &Connection,
+    experiment_id: i64,
+    drift: bool,
+) -> Result<Option<ClockFit>> {
+    /* Forget a previous alignment, which would survive a failed one */
+    connection.execute(
+        r#"DELETE FROM "clock_fit" WHERE "experiment_id" = ?"#,
+        [experiment_id],
+    )?;
+    connection.execute(
+        r#"UPDATE "server" SET "aligned_ts_ns" = NULL WHERE "experiment_id" = ?"#,
+        [experiment_id],
+    )?;
+    if !table_exists(connection, "udp")? {
+        println!("Warning: no udp table, the server clock cannot be aligned");
+        return Ok(None);
+    }
+    let fit = match ClockFit::estimate(&packet_pairs(connection, experiment_id)?, drift) {
+        Some(fit) => fit,
+        None => {
+            println!(
+                "Warning: no udp packet reached the server, the server clock cannot be aligned"
+            );
+            return Ok(None);
+        }
+    };
+    println!(
+        "Server clock offset: {:.6} s, drift: {:.3} ppm ({} packets)",
+        fit.offset_ns as f64 / 1e9,
+        fit.drift_ppm,
+        fit.pairs
+    );
+
+    connection.execute(
+        r#"INSERT INTO "clock_fit"
+           ("experiment_id", "offset_ns", "drift_ppm", "reference_ts_ns", "pairs", "windows", "residual_ns")
+           VALUES (?, ?, ?, ?, ?, ?, ?)"#,
+        params![
+            experiment_id,
+            fit.offset_ns,
+            fit.drift_ppm,
+            fit.reference_ts_ns,
+            fit.pairs as i64,
+            fit.windows as i64,
+            fit.residual_ns
+        ],
+    )?;
+    /* Same computation as ClockFit::align */
+    connection.execute(
+        r#"UPDATE "server" SET "aligned_ts_ns" = "ts_ns" - ?1
+             - CAST(round(?2 * ("ts_ns" - ?1 - ?3) / (1.0 + ?2)) AS INTEGER)
+           WHERE "experiment_id" = ?4"#,
+        params![
+            fit.offset_ns,
+            fit.drift_ppm / 1e6,
+            fit.reference_ts_ns,
+            experiment_id
+        ],
+    )?;
+    Ok(Some(fit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::Schema;
+    use crate::sqlite::SqliteSink;
+
+    const SECOND: i64 = 1_000_000_000;
+
+    /// Pairs sent every second for ten minutes, received after `delay` plus
+    /// a queueing delay of up to 40 ms, by a server clock `offset` ahead and
+    /// drifting by `ppm`
+    fn pairs(offset: i64, ppm: f64, delay: i64) -> Vec<(i64, i64)> {
+        (0..600)
+            .map(|i| {
+                let send = 1_612_345_678 * SECOND + i * SECOND;
+                let queueing = (i * 7919 % 41) * 1_000_000;
+                let drift = (ppm * 1e-6 * (i * SECOND) as f64).round() as i64;
+                (send, send + offset + drift + delay + queueing)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn offset_is_the_smallest_delay() {
+        let fit = ClockFit::estimate(&pairs(2 * SECOND, 0.0, 5_000_000), false).unwrap();
+        assert_eq!(fit.offset_ns, 2 * SECOND + 5_000_000);
+        assert_eq!(fit.drift_ppm, 0.0);
+        assert_eq!((fit.pairs, fit.windows), (600, 10));
+        assert_eq!(
+            fit.align(1_612_345_679 * SECOND + fit.offset_ns),
+            1_612_345_679 * SECOND
+        );
+    }
+
+    #[test]
+    fn drift_is_estimated() {
+        let pairs = pairs(-SECOND, 20.0, 5_000_000);
+        let fit = ClockFit::estimate(&pairs, true).unwrap();
+        assert!((fit.drift_ppm - 20.0).abs() < 1.0, "{}", fit.drift_ppm);
+        /* The fit lies below every packet */
+        for &(send, receive) in &pairs {
+            assert!(fit.align(receive) >= send - 1_000);
+        }
+        /* Without drift, the offset is the smallest delay */
+        let fit = ClockFit::estimate(&pairs, false).unwrap();
+        assert_eq!(fit.offset_ns, -SECOND + 5_000_000);
+    }
+
+    #[test]
+    fn no_pairs_no_fit() {
+        assert_eq!(ClockFit::estimate(&[], true), None);
+        let fit = ClockFit::estimate(&[(10, 15)], true).unwrap();
+        assert_eq!((fit.offset_ns, fit.drift_ppm, fit.windows), (5, 0.0, 1));
+    }
+
+    #[test]
+    fn packets_are_matched_by_port() {
+        let sink = SqliteSink::open(":memory:", &Schema::default()).unwrap();
+        let connection = sink.connection();
+        connection
+            .execute_batch(&format!(
+                r#"INSERT INTO "udp" ("experiment_id", "ts_ns", "destination port", "payload")
+                   VALUES (1, 0, 1000, 'a'), (1, {0}, 2000, 'a');
+                   INSERT INTO "server" ("experiment_id", "ts_ns", "receiver port", "payload")
+                   VALUES (1, {1}, 2000, 'a'), (1, {2}, 1000, 'a');"#,
+                10 * SECOND,
+                10 * SECOND + SECOND / 2,
+                SECOND / 5
+            ))
+            .unwrap();
+        assert_eq!(
+            packet_pairs(connection, 1).unwrap(),
+            [(0, SECOND / 5), (10 * SECOND, 10 * SECOND + SECOND / 2)]
+        );
+
+        let fit = align_clocks(connection, 1, false).unwrap().unwrap();
+        assert_eq!(fit.offset_ns, SECOND / 5);
+        let fits = |connection: &Connection| -> i64 {
+            connection
+                .query_row(
+                    r#"SELECT count(*) FROM "clock_fit" WHERE "experiment_id" = 1"#,
+                    [],
+                    |row| row.get(0),
+                )
+                .unwrap()
+        };
+        assert_eq!(fits(connection), 1);
+
+        /* A failed alignment does not leave the previous one behind */
+        connection.execute_batch(r#"DELETE FROM "udp""#).unwrap();
+        assert_eq!(align_clocks(connection, 1, false).unwrap(), None);
+        assert_eq!(fits(connection), 0);
+        let aligned: i64 = connection
+            .query_row(
+                r#"SELECT count("aligned_ts_ns") FROM "server""#,
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(aligned, 0);
+    }
+}
@@ -0,0 +1,280 @@
+use crate::analysis::table_exists;
+use crate::error::Result;
+use crate::migrations::columns;
+use crate::sqlite::quote;
+use rusqlite::Connection;
+
+/// The cumulative counters of the serial tables, which restart from zero
+/// when a node reboots: the table, the columns identifying a set of
+/// counters within a node, and the counters
+pub(crate) const COUNTERS: &[(&str, &[&str], &[&str])] = &[
+    (
+        "stats",
+        &["layer"],
+        &[
+            "rx packets",
+            "rx bytes",
+            "tx packets",
+            "tx multicast packets",
+            "tx bytes",
+            "tx succeeded",
+            "tx errors",
+        ],
+    ),
+    (
+        "rpl_stats",
+        &["Packet Type", "Measurement Type"],
+        &["RX unicast", "TX unicast", "RX multicast", "TX multicast"],
+    ),
+    ("neighbor_stats", &["L2 address"], &["sent", "received"]),
+];
+
+/// The `counters` which exist in `table`, or `None` when the table or one
+/// of its `keys` does not exist, or when none of the counters does
+pub(crate) fn available_counters<'a>(
+    connection: &Connection,
+    table: &str,
+    keys: &[&str],
+    counters: &[&'a str],
+) -> Result<Option<Vec<&'a str>>> {
+    if !table_exists(connection, table)? {
+        return Ok(None);
+    }
+    let available = columns(connection, table)?;
+    let counters: Vec<&str> = counters
+        .iter()
+        .copied()
+        .filter(|c| available.iter().any(|a| a == c))
+        .collect();
+    if counters.is_empty() || !keys.iter().all(|k| available.iter().any(|a| a == k)) {
+        return Ok(None);
+    }
+    Ok(Some(counters))
+}
+
+/// The name of the view of the deltas of the counters of `table`
+pub fn deltas_view(table: &str) -> String {
+    format!("{}_deltas", table)
+}
+
+/// Creates, for each table of cumulative counters, a view of the increments
+/// of its counters between consecutive samples of the same node, set of
+/// counters and boot (`<counter> delta`), and of their rate in units per
+/// second (`<counter> rate`), along with the time since the previous
+/// sample (`interval_ns`).
+///
+/// The first sample of a boot has no rate; its delta is the counter itself
+/// after a reboot, since the counters restart from zero, and is unknown for
+/// the first boot. Within a boot, a counter lower than in the previous
+/// sample restarted from zero as well, as the counters of a neighbor do when
+/// its entry is evicted from the neighbor table.
+pub fn create_delta_views(connection: &Connection) -> Result<()> {
+    for (table, keys, counters) in COUNTERS {
+        let view = quote(&deltas_view(table));
+        connection.execute_batch(&format!("DROP VIEW IF EXISTS {};", view))?;
+        let counters = match available_counters(connection, table, keys, counters)? {
+            Some(counters) => counters,
+            None => continue,
+        };
+        let keys: Vec<String> = keys.iter().map(|k| quote(k)).collect();
+        let mut columns = vec![
+            r#""experiment_id", "Node", "boot""#.to_string(),
+            keys.join(", "),
+            r#""Timestamp", "ts_ns", "ts_ns" - LAG("ts_ns") OVER w AS "interval_ns""#.to_string(),
+        ];
+        for counter in &counters {
+            let (name, delta) = (
+                quote(counter),
+                format!(
+                    "(CASE WHEN {0} < LAG({0}) OVER w THEN {0} ELSE {0} - LAG({0}) OVER w END)",
+                    quote(counter)
+                ),
+            );
+            columns.push(format!(
+                r#"CASE WHEN LAG("ts_ns") OVER w IS NOT NULL THEN {delta}
+                     WHEN "boot" > 0 THEN {name} END AS {alias}"#,
+                delta = delta,
+                name = name,
+                alias = quote(&format!("{} delta", counter))
+            ));
+            columns.push(format!(
+                r#"{delta} * 1e9 / ("ts_ns" - LAG("ts_ns") OVER w) AS {alias}"#,
+                delta = delta,
+                alias = quote(&format!("{} rate", counter))
+            ));
+        }
+        connection.execute_batch(&format!(
+            r#"CREATE VIEW {view} AS
+               SELECT {columns}
+               FROM {table}
+               WINDOW w AS (PARTITION BY "experiment_id", "Node", "boot", {keys} ORDER BY "ts_ns");"#,
+            view = view,
+            columns = columns.join(",\n                      "),
+            table = quote(table),
+            keys = keys.join(", ")
+        ))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::open_database;
+
+    const SECOND: i64 = 1_000_000_000;
+
+    /// The interval, delta and rate of `counter` in the rows of `view`
+    /// selected by `filter`, in time order
+    fn deltas(
+        connection: &Connection,
+        view: &str,
+        counter: &str,
+        filter: &str,
+    ) -> Vec<(Option<i64>, Option<i64>, Option<f64>)> {
+        connection
+            .prepare(&format!(
+                r#"SELECT "interval_ns", {}, {} FROM {} WHERE {}
+                   ORDER BY "ts_ns", "interval_ns" DESC"#,
+                quote(&format!("{} delta", counter)),
+                quote(&format!("{} rate", counter)),
+                quote(view),
+                filter
+            ))
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    fn database() -> Connection {
+        let connection = open_database(":memory:").unwrap();
+        connection
+            .execute_batch(&format!(
+                r#"CREATE TABLE "stats" ("experiment_id" INTEGER, "Node" TEXT, "boot" INTEGER,
+                     "layer" TEXT, "Timestamp" TEXT, "ts_ns" INTEGER, "rx packets" INTEGER);
+                   INSERT INTO "stats" VALUES
+                     (1, 'm3-1', 0, 'ip', NULL, 0, 10), (1, 'm3-1', 0, 'mac', NULL, {half}, 100),
+                     (1, 'm3-1', 0, 'ip', NULL, {one}, 15), (1, 'm3-2', 0, 'ip', NULL, {one}, 1),
+                     (1, 'm3-1', 0, 'mac', NULL, {one} + {half}, 110),
+                     (1, 'm3-1', 1, 'ip', NULL, {two}, 3), (1, 'm3-1', 1, 'ip', NULL, {three}, 7);
+                   CREATE TABLE "rpl_stats" ("experiment_id" INTEGER, "Node" TEXT,
+                     "boot" INTEGER, "Packet Type" TEXT, "Measurement Type" TEXT,
+                     "Timestamp" TEXT, "ts_ns" INTEGER, "RX unicast" INTEGER);
+                   INSERT INTO "rpl_stats" VALUES
+                     (1, 'm3-1', 0, 'DIO', 'count', NULL, 0, 4),
+                     (1, 'm3-1', 0, 'DAO', 'count', NULL, 0, 40),
+                     (1, 'm3-1', 0, 'DIO', 'bytes', NULL, 0, 400),
+                     (1, 'm3-1', 0, 'DIO', 'count', NULL, {one}, 6),
+                     (1, 'm3-1', 0, 'DAO', 'count', NULL, {one}, 41),
+                     (1, 'm3-1', 0, 'DIO', 'bytes', NULL, {one}, 460);
+                   CREATE TABLE "neighbor_stats" ("experiment_id" INTEGER, "Node" TEXT,
+                     "boot" INTEGER, "L2 address" TEXT, "Timestamp" TEXT, "ts_ns" INTEGER,
+                     "sent" INTEGER);
+                   INSERT INTO "neighbor_stats" VALUES
+                     (1, 'm3-1', 0, '2a:ab', NULL, 0, 5), (1, 'm3-1', 0, '2a:ac', NULL, 0, 50),
+                     (1, 'm3-1', 0, '2a:ab', NULL, {one}, 8), (1, 'm3-1', 0, '2a:ac', NULL, {one}, 2),
+                     (1, 'm3-1', 0, '2a:ab', NULL, {one}, 8);"#,
+                half = SECOND / 2,
+                one = SECOND,
+                two = 2 * SECOND,
+                three = 3 * SECOND
+            ))
+            .unwrap();
+        create_delta_views(&connection).unwrap();
+        connection
+    }
+
+    #[test]
+    fn deltas_restart_with_each_boot() {
+        let connection = database();
+        assert_eq!(
+            deltas(
+                &connection,
+                "stats_deltas",
+                "rx packets",
+                r#""Node" = 'm3-1' AND "layer" = 'ip'"#
+            ),
+            [
+                /* The first sample of the first boot has no delta */
+                (None, None, None),
+                (Some(SECOND), Some(5), Some(5.0)),
+                /* After a reboot, the counter restarted from zero */
+                (None, Some(3), None),
+                (Some(SECOND), Some(4), Some(4.0)),
+            ]
+        );
+        /* Each node has its own counters */
+        assert_eq!(
+            deltas(
+                &connection,
+                "stats_deltas",
+                "rx packets",
+                r#""Node" = 'm3-2'"#
+            ),
+            [(None, None, None)]
+        );
+    }
+
+    #[test]
+    fn deltas_are_computed_within_each_set_of_counters() {
+        let connection = database();
+        assert_eq!(
+            deltas(
+                &connection,
+                "stats_deltas",
+                "rx packets",
+                r#""layer" = 'mac'"#
+            ),
+            [(None, None, None), (Some(SECOND), Some(10), Some(10.0))]
+        );
+        let rpl = |filter: &str| {
+            deltas(&connection, "rpl_stats_deltas", "RX unicast", filter)
+                .into_iter()
+                .map(|(_, delta, _)| delta)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            rpl(r#""Packet Type" = 'DIO' AND "Measurement Type" = 'count'"#),
+            [None, Some(2)]
+        );
+        assert_eq!(
+            rpl(r#""Packet Type" = 'DAO' AND "Measurement Type" = 'count'"#),
+            [None, Some(1)]
+        );
+        assert_eq!(
+            rpl(r#""Packet Type" = 'DIO' AND "Measurement Type" = 'bytes'"#),
+            [None, Some(60)]
+        );
+    }
+
+    #[test]
+    fn decreases_and_simultaneous_samples() {
+        let connection = database();
+        /* The neighbor was evicted, and its counter restarted from zero */
+        assert_eq!(
+            deltas(
+                &connection,
+                "neighbor_stats_deltas",
+                "sent",
+                r#""L2 address" = '2a:ac'"#
+            ),
+            [(None, None, None), (Some(SECOND), Some(2), Some(2.0))]
+        );
+        /* Two samples at the same time have no rate */
+        assert_eq!(
+            deltas(
+                &connection,
+                "neighbor_stats_deltas",
+                "sent",
+                r#""L2 address" = '2a:ab'"#
+            ),
+            [
+                (None, None, None),
+                (Some(SECOND), Some(3), Some(3.0)),
+                (Some(0), Some(0), None),
+            ]
+        );
+    }
+}
@@ -0,0 +1,143 @@
+use crate::analysis::table_exists;
+use crate::error::Result;
+use rusqlite::Connection;
+
+/// Condition matching a server row `s` with the udp row `u` of the packet it
+/// received: the same payload, on the destination port of the packet when the
+/// node logged it. The clock alignment uses the same key.
+pub(crate) const SAME_PACKET: &str = r#"s."payload" = u."payload" AND (u."destination port" IS NULL OR s."receiver port" = u."destination port")"#;
+
+/// Matches each udp packet of the experiment with its receptions by the
+/// server, and writes them to the `deliveries` table.
+///
+/// A packet is matched with the server rows holding the same payload on its
+/// destination port. Its reception time is the first of them, on the aligned
+/// server clock when the clocks could be aligned, and the other ones are
+/// counted as duplicates in `receptions`.
+///
+/// Packets are told apart by their payload and port only: several packets
+/// sent with the same payload to the same port are all matched with every
+/// reception of that payload, and cannot be told from duplicates.
+pub fn correlate_deliveries(connection: &Connection, experiment_id: i64) -> Result<()> {
+    connection.execute(
+        r#"DELETE FROM "deliveries" WHERE "experiment_id" = ?"#,
+        [experiment_id],
+    )?;
+    if !table_exists(connection, "udp")? {
+        println!("Warning: no udp table, the deliveries cannot be computed");
+        return Ok(());
+    }
+    connection.execute(
+        &format!(
+            r#"INSERT INTO "deliveries"
+               ("experiment_id", "node", "payload", "send_ts_ns", "receive_ts_ns",
+                "latency_ns", "delivered", "receptions", "source_address")
+               /* The address is the one of the first reception */
+               SELECT u."experiment_id", u."Node", u."payload", u."ts_ns",
+                      MIN(coalesce(s."aligned_ts_ns", s."ts_ns")),
+                      MIN(coalesce(s."aligned_ts_ns", s."ts_ns")) - u."ts_ns",
+                      count(s.rowid) > 0, count(s.rowid), s."IPv6 Adress"
+               FROM "udp" u LEFT JOIN "server" s
+                 ON s."experiment_id" = u."experiment_id" AND {}
+               WHERE u."experiment_id" = ?1
+               GROUP BY u.rowid"#,
+            SAME_PACKET
+        ),
+        [experiment_id],
+    )?;
+
+    let (sent, delivered, duplicates): (i64, i64, i64) = connection.query_row(
+        r#"SELECT count(*), coalesce(sum("delivered"), 0),
+                  coalesce(sum(max("receptions" - 1, 0)), 0)
+           FROM "deliveries" WHERE "experiment_id" = ?"#,
+        [experiment_id],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )?;
+    let unmatched: i64 = connection.query_row(
+        &format!(
+            r#"SELECT count(*) FROM "server" s
+               WHERE s."experiment_id" = ?1 AND NOT EXISTS (
+                 SELECT 1 FROM "udp" u WHERE u."experiment_id" = ?1 AND {}
+               )"#,
+            SAME_PACKET
+        ),
+        [experiment_id],
+        |row| row.get(0),
+    )?;
+    println!(
+        "{} packets sent, {} delivered, {} duplicate receptions, {} receptions of unknown packets",
+        sent, delivered, duplicates, unmatched
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::Schema;
+    use crate::sqlite::SqliteSink;
+
+    const SECOND: i64 = 1_000_000_000;
+
+    /// The node, payload, latency, delivered flag and receptions of the
+    /// deliveries of experiment 1, by send time
+    fn deliveries(connection: &Connection) -> Vec<(String, String, Option<i64>, bool, i64)> {
+        connection
+            .prepare(
+                r#"SELECT "node", "payload", "latency_ns", "delivered", "receptions"
+                   FROM "deliveries" WHERE "experiment_id" = 1 ORDER BY "send_ts_ns""#,
+            )
+            .unwrap()
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn duplicates_and_losses_are_counted() {
+        let sink = SqliteSink::open(":memory:", &Schema::default()).unwrap();
+        let connection = sink.connection();
+        connection
+            .execute_batch(&format!(
+                r#"INSERT INTO "udp" ("experiment_id", "Node", "ts_ns", "destination port", "payload")
+                   VALUES (1, 'm3-1', 0, 1000, 'a'), (1, 'm3-2', {s}, 1000, 'b'),
+                          (1, 'm3-1', {two}, 1000, 'c');
+                   INSERT INTO "server" ("experiment_id", "ts_ns", "receiver port", "payload")
+                   VALUES (1, {s}, 1000, 'a'), (1, {two}, 1000, 'a'),
+                          (1, {three}, 1000, 'b'), (1, {three}, 2000, 'c'), (1, {three}, 1000, 'd');"#,
+                s = SECOND,
+                two = 2 * SECOND,
+                three = 3 * SECOND
+            ))
+            .unwrap();
+        correlate_deliveries(connection, 1).unwrap();
+        assert_eq!(
+            deliveries(connection),
+            [
+                /* Received twice: the first reception gives the latency */
+                ("m3-1".into(), "a".into(), Some(SECOND), true, 2),
+                ("m3-2".into(), "b".into(), Some(2 * SECOND), true, 1),
+                /* Only received on another port */
+                ("m3-1".into(), "c".into(), None, false, 0),
+            ]
+        );
+
+        /* Correlating again replaces the previous deliveries */
+        connection
+            .execute_batch(r#"DELETE FROM "server" WHERE "payload" = 'a'"#)
+            .unwrap();
+        correlate_deliveries(connection, 1).unwrap();
+        let lost = deliveries(connection);
+        assert_eq!(lost.len(), 3);
+        assert_eq!(lost[0], ("m3-1".into(), "a".into(), None, false, 0));
+    }
+}
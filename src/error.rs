@@ -0,0 +1,251 @@
+use std::fmt;
+use std::{num::ParseFloatError, num::ParseIntError};
+
+/// What went wrong, without the context in which it happened
+#[derive(Debug)]
+pub enum ErrorKind {
+    Io(std::io::Error),
+    Sqlite(rusqlite::Error),
+    InvalidInteger(ParseIntError),
+    InvalidReal(ParseFloatError),
+    /// The timestamp is malformed, or out of range when there is no parse error
+    InvalidTimestamp(Option<chrono::ParseError>),
+    InvalidUtf8(std::str::Utf8Error),
+    /// The line has fewer fields than expected
+    MissingField,
+    /// The tag of the line is not handled
+    UnknownTag,
+    /// The line is longer than the limit, in bytes
+    LineTooLong(usize),
+    /// An insert did not write exactly one row
+    Insert(usize),
+    /// The schema file is invalid
+    Schema(String),
+    /// The database was written with a schema version we do not know
+    UnsupportedVersion(i64),
+    /// An experiment of the same name is already stored in the database
+    ExperimentExists(String),
+    /// The input could not be read or decompressed up to its end
+    Truncated(std::io::Error),
+    /// The work was abandoned because of an error elsewhere
+    Aborted,
+    /// The histogram would have too many bins: the smallest bin width
+    /// allowed, in nanoseconds
+    TooManyBins(i64),
+}
+
+impl ErrorKind {
+    /// A short, stable identifier for the kind of error
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorKind::Io(_) => "io",
+            ErrorKind::Sqlite(_) => "sqlite",
+            ErrorKind::InvalidInteger(_) => "invalid_integer",
+            ErrorKind::InvalidReal(_) => "invalid_real",
+            ErrorKind::InvalidTimestamp(_) => "invalid_timestamp",
+            ErrorKind::InvalidUtf8(_) => "invalid_utf8",
+            ErrorKind::MissingField => "missing_field",
+            ErrorKind::UnknownTag => "unknown_tag",
+            ErrorKind::LineTooLong(_) => "line_too_long",
+            ErrorKind::Insert(_) => "insert",
+            ErrorKind::Schema(_) => "schema",
+            ErrorKind::UnsupportedVersion(_) => "unsupported_version",
+            ErrorKind::ExperimentExists(_) => "experiment_exists",
+            ErrorKind::Truncated(_) => "truncated",
+            ErrorKind::Aborted => "aborted",
+            ErrorKind::TooManyBins(_) => "too_many_bins",
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::Io(e) => write!(f, "I/O error: {}", e),
+            ErrorKind::Sqlite(e) => write!(f, "database error: {}", e),
+            ErrorKind::InvalidInteger(e) => write!(f, "invalid integer: {}", e),
+            ErrorKind::InvalidReal(e) => write!(f, "invalid real: {}", e),
+            ErrorKind::InvalidTimestamp(Some(e)) => write!(f, "invalid timestamp: {}", e),
+            ErrorKind::InvalidTimestamp(None) => write!(f, "invalid timestamp: out of range"),
+            ErrorKind::InvalidUtf8(e) => write!(f, "invalid UTF-8: {}", e),
+            ErrorKind::MissingField => write!(f, "missing field"),
+            ErrorKind::UnknownTag => write!(f, "unknown tag"),
+            ErrorKind::LineTooLong(n) => write!(f, "line longer than {} bytes", n),
+            ErrorKind::Insert(n) => write!(f, "insert wrote {} rows instead of 1", n),
+            ErrorKind::Schema(e) => write!(f, "invalid schema: {}", e),
+            ErrorKind::UnsupportedVersion(v) => write!(
+                f,
+                "database schema version {} is not supported (latest known is {})",
+                v,
+                crate::migrations::VERSION
+            ),
+            ErrorKind::ExperimentExists(name) => {
+                write!(f, "experiment {} is already in the database", name)
+            }
+            ErrorKind::Truncated(e) => write!(f, "input truncated or corrupted: {}", e),
+            ErrorKind::Aborted => write!(f, "aborted after an error in another worker"),
+            ErrorKind::TooManyBins(width) => write!(
+                f,
+                "too many histogram bins, use bins of at least {} ms",
+                *width as f64 / 1e6
+            ),
+        }
+    }
+}
+
+/// Where in the input an error happened
+#[derive(Debug, Default)]
+pub struct Context {
+    pub file: Option<String>,
+    /// Line number, starting from 1
+    pub line: Option<u64>,
+    /// Byte offset of the start of the line in the decompressed input
+    pub offset: Option<u64>,
+    pub node: Option<String>,
+    pub tag: Option<String>,
+    pub column: Option<String>,
+    /// The raw value that could not be parsed
+    pub value: Option<String>,
+}
+
+/// An error, along with where in the input it happened.
+///
+/// The context is filled in as the error travels up: the parsers know the
+/// column and the raw value, the readers know the file and the line.
+#[derive(Debug)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub context: Box<Context>,
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind) -> Self {
+        Error {
+            kind,
+            context: Box::default(),
+        }
+    }
+
+    /// Position of the offending line: file name, line number (from 1) and byte offset
+    pub fn at(mut self, file: &str, line: u64, offset: u64) -> Self {
+        self.context.file.get_or_insert_with(|| file.to_string());
+        self.context.line.get_or_insert(line);
+        self.context.offset.get_or_insert(offset);
+        self
+    }
+
+    pub fn in_file(mut self, file: &str) -> Self {
+        self.context.file.get_or_insert_with(|| file.to_string());
+        self
+    }
+
+    pub fn with_node(mut self, node: &str) -> Self {
+        self.context.node.get_or_insert_with(|| node.to_string());
+        self
+    }
+
+    pub fn with_tag(mut self, tag: &str) -> Self {
+        self.context.tag.get_or_insert_with(|| tag.to_string());
+        self
+    }
+
+    pub fn with_column(mut self, column: &str) -> Self {
+        self.context
+            .column
+            .get_or_insert_with(|| column.to_string());
+        self
+    }
+
+    pub fn with_value(mut self, value: &str) -> Self {
+        self.context.value.get_or_insert_with(|| value.to_string());
+        self
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let context = &self.context;
+        if let Some(file) = &context.file {
+            write!(f, "{}", file)?;
+            if let Some(line) = context.line {
+                write!(f, ":{}", line)?;
+            }
+            if let Some(offset) = context.offset {
+                write!(f, " (byte {})", offset)?;
+            }
+            write!(f, ": ")?;
+        }
+        write!(f, "{}", self.kind)?;
+        if let Some(column) = &context.column {
+            write!(f, " in column '{}'", column)?;
+        }
+        if let Some(value) = &context.value {
+            write!(f, " (value '{}')", value)?;
+        }
+        match (&context.node, &context.tag) {
+            (Some(node), Some(tag)) => write!(f, " [node {}, tag {}]", node, tag),
+            (Some(node), None) => write!(f, " [node {}]", node),
+            (None, Some(tag)) => write!(f, " [tag {}]", tag),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.kind {
+            ErrorKind::Io(e) => Some(e),
+            ErrorKind::Sqlite(e) => Some(e),
+            ErrorKind::InvalidInteger(e) => Some(e),
+            ErrorKind::InvalidReal(e) => Some(e),
+            ErrorKind::InvalidTimestamp(Some(e)) => Some(e),
+            ErrorKind::InvalidUtf8(e) => Some(e),
+            ErrorKind::Truncated(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Error {
+        Error::new(kind)
+    }
+}
+
+impl From<ParseIntError> for Error {
+    fn from(err: ParseIntError) -> Error {
+        Error::new(ErrorKind::InvalidInteger(err))
+    }
+}
+
+impl From<chrono::ParseError> for Error {
+    fn from(err: chrono::ParseError) -> Error {
+        Error::new(ErrorKind::InvalidTimestamp(Some(err)))
+    }
+}
+
+impl From<std::str::Utf8Error> for Error {
+    fn from(err: std::str::Utf8Error) -> Error {
+        Error::new(ErrorKind::InvalidUtf8(err))
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Error {
+        Error::new(ErrorKind::Io(err))
+    }
+}
+
+impl From<ParseFloatError> for Error {
+    fn from(err: ParseFloatError) -> Error {
+        Error::new(ErrorKind::InvalidReal(err))
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Error {
+        Error::new(ErrorKind::Sqlite(err))
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,356 @@
+use crate::analysis::{select_experiments, table_exists, value_text};
+use crate::error::Result;
+use crate::report::format_timestamp;
+use crate::topology::{interface_identifier, link_layer_identifier};
+use rusqlite::{params, Connection};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::str::FromStr;
+
+/// The output formats of the graphs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GraphFormat {
+    /// Graphviz
+    Dot,
+    GraphMl,
+}
+
+impl FromStr for GraphFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> std::result::Result<GraphFormat, String> {
+        match format {
+            "dot" => Ok(GraphFormat::Dot),
+            "graphml" => Ok(GraphFormat::GraphMl),
+            _ => Err(format!(
+                "unknown format '{}', expected dot or graphml",
+                format
+            )),
+        }
+    }
+}
+
+/// A node of the DODAG, with its last advertised rank and role
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TopologyNode {
+    pub rank: Option<String>,
+    pub role: Option<String>,
+}
+
+/// A node and its preferred parent, named by its address when unknown
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopologyEdge {
+    pub child: String,
+    pub parent: String,
+    pub instance: Option<String>,
+    /// The ETX of the parent, as last measured by the child
+    pub etx: Option<String>,
+}
+
+/// The DODAG of an experiment at a given instant
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub experiment: String,
+    pub at_ns: i64,
+    pub nodes: BTreeMap<String, TopologyNode>,
+    pub edges: Vec<TopologyEdge>,
+}
+
+impl Snapshot {
+    /// The DODAG of the experiment at `at_ns`, from the last samples taken at
+    /// or before that instant
+    pub fn at(
+        connection: &Connection,
+        experiment_id: i64,
+        experiment: &str,
+        at_ns: i64,
+    ) -> Result<Snapshot> {
+        let mut snapshot = Snapshot {
+            experiment: experiment.to_string(),
+            at_ns,
+            nodes: BTreeMap::new(),
+            edges: Vec::new(),
+        };
+
+        if table_exists(connection, "rpl_stats_dodag")? {
+            let nodes = connection
+                .prepare(
+                    r#"SELECT "Node", "Rank", "Role", max("ts_ns") FROM "rpl_stats_dodag"
+                       WHERE "experiment_id" = ? AND "ts_ns" <= ?
+                       GROUP BY "Node""#,
+                )?
+                .query_map(params![experiment_id, at_ns], |row| {
+                    let node = TopologyNode {
+                        rank: row
+                            .get::<_, Option<rusqlite::types::Value>>(1)?
+                            .map(value_text),
+                        role: row.get(2)?,
+                    };
+                    Ok((row.get::<_, String>(0)?, node))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            snapshot.nodes.extend(nodes);
+        }
+
+        /* The ETX of each neighbor of each node, by interface identifier */
+        let mut etx: HashMap<(String, u64), String> = HashMap::new();
+        if table_exists(connection, "neighbor_stats")? {
+            let mut statement = connection.prepare(
+                r#"SELECT "Node", "L2 address", "etx", max("ts_ns") FROM "neighbor_stats"
+                   WHERE "experiment_id" = ? AND "ts_ns" <= ?
+                   GROUP BY "Node", "L2 address""#,
+            )?;
+            let mut rows = statement.query(params![experiment_id, at_ns])?;
+            while let Some(row) = rows.next()? {
+                let (node, address): (String, Option<String>) = (row.get(0)?, row.get(1)?);
+                let value: Option<rusqlite::types::Value> = row.get(2)?;
+                if let (Some(iid), Some(value)) =
+                    (address.as_deref().and_then(link_layer_identifier), value)
+                {
+                    etx.insert((node, iid), value_text(value));
+                }
+            }
+        }
+
+        let edges = connection
+            .prepare(
+                r#"SELECT "child", "parent", "parent_address", "instance" FROM "topology_edges"
+                   WHERE "experiment_id" = ? AND "valid_from" <= ?2
+                     AND ("valid_to" IS NULL OR ?2 < "valid_to")
+                   ORDER BY "child", "instance""#,
+            )?
+            .query_map(params![experiment_id, at_ns], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, Option<String>>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for (child, parent, address, instance) in edges {
+            let parent = match (parent, &address) {
+                (Some(parent), _) => parent,
+                (None, Some(address)) => address.clone(),
+                (None, None) => continue,
+            };
+            let etx = address
+                .as_deref()
+                .and_then(interface_identifier)
+                .and_then(|iid| etx.get(&(child.clone(), iid)).cloned());
+            for node in [&child, &parent] {
+                snapshot.nodes.entry(node.clone()).or_default();
+            }
+            snapshot.edges.push(TopologyEdge {
+                child,
+                parent,
+                instance,
+                etx,
+            });
+        }
+        Ok(snapshot)
+    }
+
+    /// The label of a node: its name, then its rank and role after `separator`
+    fn label(name: &str, node: &TopologyNode, separator: &str) -> String {
+        match (&node.rank, &node.role) {
+            (Some(rank), Some(role)) => format!("{}{}rank {} ({})", name, separator, rank, role),
+            (Some(rank), None) => format!("{}{}rank {}", name, separator, rank),
+            (None, Some(role)) => format!("{}{}({})", name, separator, role),
+            (None, None) => name.to_string(),
+        }
+    }
+
+    /// Writes the snapshot as a Graphviz digraph, the edges going from the
+    /// children to their parents
+    pub fn write_dot(&self, out: &mut dyn Write) -> Result<()> {
+        let quote = |s: &str| {
+            format!(
+                "\"{}\"",
+                s.replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', "\\n")
+            )
+        };
+        let title = format!("{} at {}", self.experiment, format_timestamp(self.at_ns));
+        writeln!(out, "digraph {} {{", quote(&self.experiment))?;
+        writeln!(out, "  label={};", quote(&title))?;
+        writeln!(out, "  rankdir=BT;")?;
+        for (name, node) in &self.nodes {
+            let root = node
+                .role
+                .as_deref()
+                .is_some_and(|r| r.eq_ignore_ascii_case("root"));
+            writeln!(
+                out,
+                "  {} [label={}{}];",
+                quote(name),
+                quote(&Snapshot::label(name, node, "\n")),
+                if root { ", shape=doublecircle" } else { "" }
+            )?;
+        }
+        for edge in &self.edges {
+            write!(out, "  {} -> {}", quote(&edge.child), quote(&edge.parent))?;
+            if let Some(etx) = &edge.etx {
+                write!(out, " [label={}]", quote(&format!("ETX {}", etx)))?;
+            }
+            writeln!(out, ";")?;
+        }
+        writeln!(out, "}}")?;
+        Ok(())
+    }
+
+    /// Writes the snapshot as a GraphML graph, the edges going from the
+    /// children to their parents.
+    ///
+    /// The ids of the nodes are unique in the whole document, which holds the
+    /// graphs of all the experiments: they are prefixed with the id of the
+    /// graph, e.g. `my-run/m3-1`.
+    pub fn write_graphml(&self, out: &mut dyn Write) -> Result<()> {
+        let escape = |s: &str| {
+            s.replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+                .replace('"', "&quot;")
+        };
+        let id = |node: &str| escape(&format!("{}/{}", self.experiment, node));
+        writeln!(
+            out,
+            "  <graph id=\"{}\" edgedefault=\"directed\">",
+            escape(&self.experiment)
+        )?;
+        for (name, node) in &self.nodes {
+            writeln!(out, "    <node id=\"{}\">", id(name))?;
+            writeln!(
+                out,
+                "      <data key=\"label\">{}</data>",
+                escape(&Snapshot::label(name, node, ", "))
+            )?;
+            if let Some(rank) = &node.rank {
+                writeln!(out, "      <data key=\"rank\">{}</data>", escape(rank))?;
+            }
+            if let Some(role) = &node.role {
+                writeln!(out, "      <data key=\"role\">{}</data>", escape(role))?;
+            }
+            writeln!(out, "    </node>")?;
+        }
+        for edge in &self.edges {
+            writeln!(
+                out,
+                "    <edge source=\"{}\" target=\"{}\">",
+                id(&edge.child),
+                id(&edge.parent)
+            )?;
+            if let Some(instance) = &edge.instance {
+                writeln!(
+                    out,
+                    "      <data key=\"instance\">{}</data>",
+                    escape(instance)
+                )?;
+            }
+            if let Some(etx) = &edge.etx {
+                writeln!(out, "      <data key=\"etx\">{}</data>", escape(etx))?;
+            }
+            writeln!(out, "    </edge>")?;
+        }
+        writeln!(out, "  </graph>")?;
+        Ok(())
+    }
+}
+
+/// Writes the DODAG at `at_ns` of the experiments named in `names` (all of
+/// them when empty) to `out`, one graph per experiment
+pub fn export_topology(
+    connection: &Connection,
+    names: &[String],
+    at_ns: i64,
+    format: GraphFormat,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let snapshots = select_experiments(connection, names)?
+        .into_iter()
+        .map(|(id, name)| Snapshot::at(connection, id, &name, at_ns))
+        .collect::<Result<Vec<_>>>()?;
+    for snapshot in &snapshots {
+        if snapshot.edges.is_empty() {
+            eprintln!(
+                "Warning: no parent known in experiment {} at {}",
+                snapshot.experiment,
+                format_timestamp(at_ns)
+            );
+        }
+    }
+    match format {
+        GraphFormat::Dot => {
+            for snapshot in &snapshots {
+                snapshot.write_dot(out)?;
+            }
+        }
+        GraphFormat::GraphMl => {
+            writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+            writeln!(
+                out,
+                r#"<graphml xmlns="http://graphml.graphdrawing.org/xmlns">"#
+            )?;
+            for (key, domain) in &[
+                ("label", "node"),
+                ("rank", "node"),
+                ("role", "node"),
+                ("instance", "edge"),
+                ("etx", "edge"),
+            ] {
+                writeln!(
+                    out,
+                    r#"  <key id="{0}" for="{1}" attr.name="{0}" attr.type="string"/>"#,
+                    key, domain
+                )?;
+            }
+            for snapshot in &snapshots {
+                snapshot.write_graphml(out)?;
+            }
+            writeln!(out, "</graphml>")?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::open_database;
+
+    #[test]
+    fn graphml_ids_are_unique_across_experiments() {
+        let connection = open_database(":memory:").unwrap();
+        connection
+            .execute_batch(
+                r#"INSERT INTO "experiments" ("id", "Name") VALUES (1, 'run-a'), (2, 'run-b');
+                   INSERT INTO "topology_edges"
+                     ("experiment_id", "child", "parent", "valid_from", "valid_to")
+                   VALUES (1, 'm3-2', 'm3-1', 0, NULL), (2, 'm3-2', 'm3-1', 0, 5),
+                          (2, 'm3-3', 'm3-2', 0, NULL);"#,
+            )
+            .unwrap();
+        let mut out = Vec::new();
+        export_topology(&connection, &[], 10, GraphFormat::GraphMl, &mut out).unwrap();
+        let graphml = String::from_utf8(out).unwrap();
+
+        let nodes: Vec<&str> = graphml
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("<node id=\""))
+            .collect();
+        assert_eq!(
+            nodes,
+            [
+                "run-a/m3-1\">",
+                "run-a/m3-2\">",
+                "run-b/m3-2\">",
+                "run-b/m3-3\">"
+            ]
+        );
+        /* The last parents are still valid, the ones which changed are not */
+        assert!(graphml.contains(r#"<edge source="run-a/m3-2" target="run-a/m3-1">"#));
+        assert!(graphml.contains(r#"<edge source="run-b/m3-3" target="run-b/m3-2">"#));
+        assert!(!graphml.contains(r#"target="run-b/m3-1""#));
+    }
+}
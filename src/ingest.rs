@@ -0,0 +1,307 @@
+use crate::error::{Error, ErrorKind, Result};
+use crate::parser::{parse_server_line, SerialParser};
+use crate::reader::{self, LineReader};
+use crate::record::{Experiment, Reject, SerialRecord};
+use crate::schema::Schema;
+use crate::sink::RecordSink;
+use std::io::BufRead;
+use std::sync::Arc;
+
+/// Number of bytes of input written in each transaction
+const BATCH_SIZE: u64 = 100_000_000;
+
+/// Whether an input was ingested up to its end
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Complete,
+    /// The input is truncated or corrupted: the records decoded before the
+    /// break were kept, and the break recorded as a reject
+    Partial,
+}
+
+impl Status {
+    /// The status of the ingestion of two inputs
+    pub fn and(self, other: Status) -> Status {
+        match (self, other) {
+            (Status::Complete, Status::Complete) => Status::Complete,
+            _ => Status::Partial,
+        }
+    }
+}
+
+/* Number of rejected lines, and of those which are not valid UTF-8 */
+#[derive(Default)]
+struct Counts {
+    rejected: u64,
+    invalid: u64,
+}
+
+/// Writes the records of the lines of `reader` into the current batch of
+/// `sink`, committing every `BATCH_SIZE` bytes
+fn ingest_reader<S: RecordSink, T, R: BufRead>(
+    sink: &mut S,
+    reader: &mut LineReader<R>,
+    filename: &str,
+    counts: &mut Counts,
+    mut parse: impl FnMut(&str) -> Result<Option<T>>,
+    write: impl Fn(&mut S, &T) -> Result<()>,
+) -> Result<()> {
+    let mut batch_start: u64 = 0;
+    loop {
+        let (next, position) = (reader.line_number() + 1, reader.offset());
+        let line = match reader.next_line() {
+            Ok(Some(line)) => line,
+            Ok(None) => return Ok(()),
+            Err(e) => return Err(Error::new(ErrorKind::Truncated(e)).at(filename, next, position)),
+        };
+        let (number, offset) = (line.number, line.offset);
+        /* Garbled bytes (e.g. printed by a node while it resets) are not parsed */
+        let parsed = match std::str::from_utf8(line.bytes) {
+            _ if line.truncated => Err(Error::new(ErrorKind::LineTooLong(line.bytes.len()))),
+            Ok(text) => parse(text),
+            Err(e) => Err(Error::from(e)),
+        };
+        match parsed {
+            Ok(Some(record)) => write(sink, &record).map_err(|e| e.at(filename, number, offset))?,
+            Ok(None) => { /* Header line */ }
+            Err(e) => {
+                /* Unknown tags and invalid lines are kept aside so that they can be audited */
+                counts.rejected += 1;
+                let raw = String::from_utf8_lossy(line.bytes);
+                let mut reject = Reject::new(&e, &raw, filename, number, offset);
+                if let ErrorKind::InvalidUtf8(_) = e.kind {
+                    counts.invalid += 1;
+                    reject = reject.with_bytes(line.bytes);
+                }
+                sink.write_reject(&reject)?;
+            }
+        }
+
+        if reader.offset() - batch_start >= BATCH_SIZE {
+            println!("{} bytes read.", reader.offset());
+            sink.commit()?;
+            sink.begin()?;
+            batch_start = reader.offset();
+        }
+    }
+}
+
+/// Reads `filename` line by line, and writes the records `parse` builds
+/// from its lines with `write`, committing every `BATCH_SIZE` bytes.
+///
+/// Lines which cannot be parsed, including the ones which are not valid
+/// UTF-8, are written as rejects. When the input breaks (e.g. a truncated
+/// archive), the current batch is rolled back, unless `recover` is set: the
+/// records decoded so far are then committed along with a reject recording
+/// where the input broke.
+fn ingest_lines<S: RecordSink, T>(
+    sink: &mut S,
+    filename: &str,
+    recover: bool,
+    parse: impl FnMut(&str) -> Result<Option<T>>,
+    write: impl Fn(&mut S, &T) -> Result<()>,
+) -> Result<Status> {
+    let (mut reader, format) = reader::open(filename)?;
+    println!("Reading {} as {:?}", filename, format);
+    let mut counts = Counts::default();
+
+    sink.begin()?;
+    let status = match ingest_reader(sink, &mut reader, filename, &mut counts, parse, write) {
+        Ok(()) => Status::Complete,
+        Err(e) if recover && matches!(e.kind, ErrorKind::Truncated(_)) => {
+            println!("Warning: {}, keeping the data decoded so far", e);
+            let context = &e.context;
+            let bytes = reader.remainder();
+            let reject = Reject::new(
+                &e,
+                &String::from_utf8_lossy(bytes),
+                filename,
+                context.line.unwrap_or_default(),
+                context.offset.unwrap_or_default(),
+            )
+            .with_bytes(bytes);
+            sink.write_reject(&reject)?;
+            Status::Partial
+        }
+        Err(e) => {
+            /* Do not leave the transaction open */
+            sink.rollback()?;
+            return Err(e);
+        }
+    };
+    sink.commit()?;
+
+    println!(
+        "{} bytes read, {} lines rejected ({} not valid UTF-8).",
+        reader.offset(),
+        counts.rejected,
+        counts.invalid
+    );
+    Ok(status)
+}
+
+/// Parses a serial aggregator output into `sink`, storing the tags as
+/// described by `schema`
+pub fn ingest_serial<S: RecordSink>(
+    sink: &mut S,
+    schema: &Arc<Schema>,
+    filename: &str,
+    recover: bool,
+) -> Result<Status> {
+    let mut parser = SerialParser::with_schema(schema.clone());
+    ingest_lines(
+        sink,
+        filename,
+        recover,
+        |line| {
+            let record = parser.parse_line(line);
+            for warning in parser.take_warnings() {
+                println!("Warning: {}", warning);
+            }
+            let record = record?;
+            /* The reboots detected on the line come before its record */
+            let mut records: Vec<SerialRecord> = parser
+                .take_reboots()
+                .into_iter()
+                .map(SerialRecord::Reboot)
+                .collect();
+            records.extend(record);
+            Ok(if records.is_empty() {
+                None
+            } else {
+                Some(records)
+            })
+        },
+        |sink, records| records.iter().try_for_each(|r| sink.write_serial(r)),
+    )
+}
+
+/// Parses a server output into `sink`
+pub fn ingest_server<S: RecordSink>(sink: &mut S, filename: &str, recover: bool) -> Result<Status> {
+    let mut is_header = true;
+    ingest_lines(
+        sink,
+        filename,
+        recover,
+        |line| {
+            if is_header {
+                is_header = false;
+                return Ok(None);
+            }
+            parse_server_line(line).map(Some)
+        },
+        |sink, record| sink.write_server(record),
+    )
+}
+
+/// Parses the serial and server files of `experiment` into `sink`, tagging
+/// the records with the experiment.
+///
+/// When the ingestion fails, the experiment is removed from the sink, so
+/// that it can be ingested again.
+pub fn ingest_experiment<S: RecordSink>(
+    sink: &mut S,
+    schema: &Arc<Schema>,
+    experiment: &Experiment,
+    recover: bool,
+) -> Result<Status> {
+    sink.begin_experiment(experiment)?;
+    let result = (|| {
+        println!("Parsing serial data.");
+        let serial = ingest_serial(sink, schema, &experiment.serial_file, recover)?;
+        println!("Parsing server data");
+        let server = ingest_server(sink, &experiment.server_file, recover)?;
+        Ok(serial.and(server))
+    })();
+    match result {
+        Ok(status) => {
+            sink.end_experiment()?;
+            Ok(status)
+        }
+        Err(e) => {
+            sink.abort_experiment()?;
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sqlite::SqliteSink;
+    use std::path::Path;
+
+    /// Writes an experiment whose serial file is a zstd stream cut in its
+    /// middle, and returns it along with the number of serial lines
+    fn truncated_experiment(directory: &Path) -> (Experiment, i64) {
+        let mut serial = String::from("1.0;m3-1;energy,cpu,radio\n");
+        /* Numbers which do not compress too well, so that the cut falls inside the data */
+        let mut state: u64 = 42;
+        let lines = 20_000;
+        for i in 0..lines {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1);
+            serial += &format!("{}.5;m3-1;energy,{},{}\n", i + 2, state >> 40, state >> 50);
+        }
+        let compressed = zstd::encode_all(serial.as_bytes(), 0).unwrap();
+        let serial_file = directory.join("serial.zst");
+        std::fs::write(&serial_file, &compressed[..compressed.len() / 2]).unwrap();
+        let server_file = directory.join("server.zst");
+        let server = "Timestamp,IPv6 Adress,receiver port,payload\n";
+        std::fs::write(
+            &server_file,
+            zstd::encode_all(server.as_bytes(), 0).unwrap(),
+        )
+        .unwrap();
+        let experiment = Experiment::new(
+            "cut",
+            &serial_file.to_string_lossy(),
+            &server_file.to_string_lossy(),
+        );
+        (experiment, lines)
+    }
+
+    fn count(sink: &SqliteSink, query: &str) -> i64 {
+        sink.connection()
+            .query_row(query, [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn truncated_inputs_are_recovered() {
+        let directory =
+            std::env::temp_dir().join(format!("moulinette-ingest-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        let (experiment, lines) = truncated_experiment(&directory);
+        let schema = Arc::new(Schema::default());
+        let mut sink = SqliteSink::open(":memory:", &schema).unwrap();
+
+        /* Without --recover, the experiment is dropped */
+        let error = ingest_experiment(&mut sink, &schema, &experiment, false).unwrap_err();
+        assert_eq!(error.kind.name(), "truncated");
+        assert_eq!(count(&sink, "SELECT count(*) FROM experiments"), 0);
+        assert_eq!(count(&sink, "SELECT count(*) FROM rejects"), 0);
+
+        let status = ingest_experiment(&mut sink, &schema, &experiment, true).unwrap();
+        assert_eq!(status, Status::Partial);
+        assert_eq!(count(&sink, "SELECT count(*) FROM experiments"), 1);
+        let kept = count(&sink, "SELECT count(*) FROM energy WHERE experiment_id = 1");
+        assert!(kept > 0 && kept < lines, "{} records kept", kept);
+        let (kind, line, offset): (String, i64, i64) = sink
+            .connection()
+            .query_row(
+                r#"SELECT "Error Kind", "Line Number", "Byte Offset" FROM rejects"#,
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(kind, "truncated");
+        /* The break follows the header and the kept records */
+        assert_eq!(line, kept + 2);
+        assert!(offset > 0);
+        assert_eq!(count(&sink, "SELECT count(*) FROM rejects"), 1);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
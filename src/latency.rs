@@ -0,0 +1,143 @@
+use crate::error::Result;
+use rusqlite::{params, Connection};
+
+/// The distribution of the latencies of a set of delivered packets
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatencySummary {
+    pub packets: usize,
+    pub min_ns: i64,
+    pub mean_ns: f64,
+    pub median_ns: i64,
+    pub p95_ns: i64,
+    pub p99_ns: i64,
+    pub max_ns: i64,
+}
+
+impl LatencySummary {
+    /// Summarizes the `latencies`, sorting them in place.
+    ///
+    /// The percentiles use the nearest rank: they are latencies of actual
+    /// packets, never interpolated.
+    pub fn new(latencies: &mut [i64]) -> Option<LatencySummary> {
+        if latencies.is_empty() {
+            return None;
+        }
+        latencies.sort_unstable();
+        let n = latencies.len();
+        let percentile = |p: f64| latencies[((p * n as f64).ceil() as usize).clamp(1, n) - 1];
+        Some(LatencySummary {
+            packets: n,
+            min_ns: latencies[0],
+            mean_ns: latencies.iter().map(|l| *l as f64).sum::<f64>() / n as f64,
+            median_ns: percentile(0.5),
+            p95_ns: percentile(0.95),
+            p99_ns: percentile(0.99),
+            max_ns: latencies[n - 1],
+        })
+    }
+}
+
+/// The latencies of the delivered packets of the experiment, by node
+pub(crate) fn node_latencies(
+    connection: &Connection,
+    experiment_id: i64,
+) -> Result<Vec<(String, Vec<i64>)>> {
+    let mut nodes: Vec<(String, Vec<i64>)> = Vec::new();
+    let mut statement = connection.prepare(
+        r#"SELECT "node", "latency_ns" FROM "deliveries"
+           WHERE "experiment_id" = ? AND "delivered" AND "latency_ns" IS NOT NULL
+           ORDER BY "node""#,
+    )?;
+    let mut rows = statement.query([experiment_id])?;
+    while let Some(row) = rows.next()? {
+        let (node, latency): (String, i64) = (row.get(0)?, row.get(1)?);
+        match nodes.last_mut() {
+            Some((last, latencies)) if *last == node => latencies.push(latency),
+            _ => nodes.push((node, vec![latency])),
+        }
+    }
+    Ok(nodes)
+}
+
+/// Writes the latency statistics of the delivered packets of the
+/// experiment, per node and overall (with no node), to `latency_summary`
+pub fn summarize_latencies(connection: &Connection, experiment_id: i64) -> Result<()> {
+    connection.execute(
+        r#"DELETE FROM "latency_summary" WHERE "experiment_id" = ?"#,
+        [experiment_id],
+    )?;
+    let nodes = node_latencies(connection, experiment_id)?;
+    let mut all: Vec<i64> = nodes.iter().flat_map(|(_, l)| l.iter().cloned()).collect();
+    let summaries = nodes
+        .into_iter()
+        .map(|(node, mut latencies)| (Some(node), LatencySummary::new(&mut latencies)))
+        .chain(Some((None, LatencySummary::new(&mut all))));
+
+    let mut insert = connection.prepare(
+        r#"INSERT INTO "latency_summary"
+           ("experiment_id", "node", "packets", "min_ns", "mean_ns",
+            "median_ns", "p95_ns", "p99_ns", "max_ns")
+           VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)"#,
+    )?;
+    for (node, summary) in summaries {
+        if let Some(s) = summary {
+            if node.is_none() {
+                println!(
+                    "Latency: median {:.3} ms, 95th percentile {:.3} ms",
+                    s.median_ns as f64 / 1e6,
+                    s.p95_ns as f64 / 1e6
+                );
+            }
+            insert.execute(params![
+                experiment_id,
+                node,
+                s.packets as i64,
+                s.min_ns,
+                s.mean_ns,
+                s.median_ns,
+                s.p95_ns,
+                s.p99_ns,
+                s.max_ns
+            ])?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_use_the_nearest_rank() {
+        let mut latencies: Vec<i64> = (1..=100).rev().collect();
+        let summary = LatencySummary::new(&mut latencies).unwrap();
+        assert_eq!(summary.packets, 100);
+        assert_eq!((summary.min_ns, summary.max_ns), (1, 100));
+        assert_eq!(summary.mean_ns, 50.5);
+        assert_eq!(summary.median_ns, 50);
+        assert_eq!(summary.p95_ns, 95);
+        assert_eq!(summary.p99_ns, 99);
+
+        let summary = LatencySummary::new(&mut [30, 10, 20]).unwrap();
+        assert_eq!(
+            (summary.median_ns, summary.p95_ns, summary.p99_ns),
+            (20, 30, 30)
+        );
+    }
+
+    #[test]
+    fn single_and_no_packet() {
+        let summary = LatencySummary::new(&mut [7]).unwrap();
+        assert_eq!(
+            (
+                summary.min_ns,
+                summary.median_ns,
+                summary.p99_ns,
+                summary.max_ns
+            ),
+            (7, 7, 7, 7)
+        );
+        assert!(LatencySummary::new(&mut []).is_none());
+    }
+}
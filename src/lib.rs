@@ -0,0 +1,51 @@
+//! Conversion of the data generated by the Riot RPL UDP experimental campaign
+//! from its original CSV-like format to typed records, and storage of these
+//! records into a `RecordSink` such as a sqlite3 database.
+
+pub mod analysis;
+pub mod campaign;
+pub mod churn;
+pub mod clock;
+pub mod counters;
+pub mod deliveries;
+pub mod error;
+pub mod export;
+pub mod ingest;
+pub mod latency;
+pub mod migrations;
+pub mod parallel;
+pub mod parser;
+pub mod reader;
+pub mod reboots;
+pub mod record;
+pub mod report;
+pub mod schema;
+pub mod sink;
+pub mod sqlite;
+pub mod topology;
+
+pub use analysis::{analyze, analyze_database, open_database, AnalysisOptions, AnalysisOutcomes};
+pub use campaign::{Campaign, Skipped};
+pub use churn::detect_parent_changes;
+pub use clock::{align_clocks, ClockFit};
+pub use counters::{create_delta_views, deltas_view};
+pub use deliveries::correlate_deliveries;
+pub use error::{Context, Error, ErrorKind, Result};
+pub use export::{export_topology, GraphFormat, Snapshot, TopologyEdge, TopologyNode};
+pub use ingest::{ingest_experiment, ingest_serial, ingest_server, Status};
+pub use latency::{summarize_latencies, LatencySummary};
+pub use parallel::{ingest_shared, ingest_split, Outcomes};
+pub use parser::{
+    default_header, infer_value, parse_serial_record, parse_server_line, parse_timestamp,
+    parse_with_header, SerialParser,
+};
+pub use reader::{Format, Line, LineReader};
+pub use reboots::detect_reboots;
+pub use record::*;
+pub use report::{
+    churn, latency, latency_histogram, latency_series, parent_durations, pdr, OutputFormat, Report,
+};
+pub use schema::{ColumnSchema, ColumnType, Schema, TagSchema};
+pub use sink::{MemorySink, RecordSink};
+pub use sqlite::SqliteSink;
+pub use topology::{build_topology, interface_identifier, link_layer_identifier};
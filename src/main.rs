@@ -1,793 +1,513 @@
-extern crate zstd;
-use chrono::format::ParseError;
-use chrono::{offset::TimeZone, DateTime, FixedOffset, Local, NaiveDateTime, Utc};
-use clap::{AppSettings, Clap};
-use rusqlite::{params, Connection, Result, Transaction};
-use std::fs::File;
-use std::io::Read;
-use std::{
-    collections::{HashMap, HashSet},
-    num::ParseFloatError,
-    num::ParseIntError,
+use chrono::{DateTime, NaiveDateTime};
+use clap::{AppSettings, ArgSettings, Clap};
+use moulinette::{
+    analyze_database, churn, export_topology, ingest_shared, ingest_split, latency,
+    latency_histogram, latency_series, open_database, parent_durations, parse_timestamp, pdr,
+    AnalysisOptions, AnalysisOutcomes, Campaign, ErrorKind, Experiment, GraphFormat, OutputFormat,
+    Result, Schema, Status,
 };
+use std::path::Path;
+use std::sync::Arc;
+
 #[derive(Clap)]
 #[clap(version = "1.0", author = "Rémy Grünblatt <remy@grunblatt.org>")]
 #[clap(setting = AppSettings::ColoredHelp)]
+#[clap(setting = AppSettings::SubcommandsNegateReqs)]
 struct Opts {
-    output: String,
-    input_serial: String,
-    input_server: String,
+    #[clap(setting = ArgSettings::Required)]
+    output: Option<String>,
+    #[clap(setting = ArgSettings::Required)]
+    input_serial: Option<String>,
+    #[clap(setting = ArgSettings::Required)]
+    input_server: Option<String>,
+    /// TOML file describing the tables of the serial tags, merged with the
+    /// built-in schema
+    #[clap(long, global = true)]
+    schema: Option<String>,
+    /// Name of the experiment, defaults to the name of the serial file
+    #[clap(long)]
+    name: Option<String>,
+    /// Parameter of the experiments, as key=value (can be repeated)
+    #[clap(long = "param", global = true, parse(try_from_str = parse_parameter))]
+    parameters: Vec<(String, String)>,
+    /// Number of worker threads: files are parsed concurrently, and written
+    /// through a single writer (or into their own database with --split)
+    #[clap(long, short, global = true, default_value = "1")]
+    jobs: usize,
+    /// Keep the data decoded from truncated or corrupted inputs instead of
+    /// failing, and exit with status 2
+    #[clap(long, global = true)]
+    recover: bool,
+    /// Estimate the drift of the server clock along with its offset
+    #[clap(long, global = true)]
+    drift: bool,
+    #[clap(subcommand)]
+    command: Option<Command>,
 }
 
-#[derive(Debug)]
-enum MyError {
-    E1(ParseIntError),
-    E2(),
-    E3(ParseError),
-    E4(std::io::Error),
-    E5(ParseFloatError),
-    E6(rusqlite::Error),
+#[derive(Clap)]
+enum Command {
+    /// Ingests every experiment of a campaign directory
+    Campaign(CampaignOpts),
+    /// Computes again the derived tables of the experiments of a database
+    Analyze(AnalyzeOpts),
+    /// Prints reports on the analyzed experiments of a database
+    Report(ReportOpts),
+    /// Exports the analyzed experiments of a database to other formats
+    Export(ExportOpts),
 }
 
-impl From<ParseIntError> for MyError {
-    fn from(err: ParseIntError) -> MyError {
-        MyError::E1(err)
-    }
+#[derive(Clap)]
+struct CampaignOpts {
+    /// Root directory of the campaign
+    directory: String,
+    /// Database to write, or directory of the databases with --split
+    output: String,
+    /// Write one database per experiment into the output directory
+    #[clap(long)]
+    split: bool,
 }
 
-impl From<ParseError> for MyError {
-    fn from(err: ParseError) -> MyError {
-        MyError::E3(err)
-    }
+#[derive(Clap)]
+struct AnalyzeOpts {
+    /// Database to analyze
+    database: String,
+    /// Name of an experiment to analyze (can be repeated), defaults to all
+    #[clap(long = "experiment")]
+    experiments: Vec<String>,
 }
 
-impl From<std::io::Error> for MyError {
-    fn from(err: std::io::Error) -> MyError {
-        MyError::E4(err)
-    }
+#[derive(Clap)]
+struct ReportOpts {
+    #[clap(subcommand)]
+    report: ReportCommand,
 }
 
-impl From<ParseFloatError> for MyError {
-    fn from(err: ParseFloatError) -> MyError {
-        MyError::E5(err)
-    }
+#[derive(Clap)]
+enum ReportCommand {
+    /// Packet delivery ratio, overall, per node and per time window
+    Pdr(PdrOpts),
+    /// Latency statistics per node and overall, their histogram, or their
+    /// evolution over time
+    Latency(LatencyOpts),
+    /// Parent changes per hour of each node, or the time spent with each
+    /// parent
+    Churn(ChurnOpts),
 }
 
-impl From<rusqlite::Error> for MyError {
-    fn from(err: rusqlite::Error) -> MyError {
-        MyError::E6(err)
-    }
+#[derive(Clap)]
+struct PdrOpts {
+    /// Database to read
+    database: String,
+    /// Name of an experiment to report on (can be repeated), defaults to all
+    #[clap(long = "experiment")]
+    experiments: Vec<String>,
+    /// Width of the time windows, in seconds
+    #[clap(long, parse(try_from_str = parse_seconds))]
+    window: Option<i64>,
+    /// Output format: table, csv or json
+    #[clap(long, default_value = "table")]
+    format: OutputFormat,
 }
 
-fn handle_neighbor_stats(
-    transaction: &Transaction,
-    timestamp: &str,
-    node: &str,
-    data: &Vec<&str>,
-) -> Result<(), MyError> {
-    let mut stmt = transaction
-        .prepare_cached(
-            "INSERT INTO 'neighbor_stats'
-        (
-            'Timestamp',
-            'Node',
-            'L2 address',
-            'fresh',
-            'etx',
-            'sent',
-            'received',
-            'rssi (dBm)',
-            'lqi',
-            'avg tx time (µs)'
-          ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
-        )
-        .unwrap();
-    let res = stmt.execute(params![
-        timestamp,
-        node,
-        data[1],
-        data[2],
-        data[3],
-        data[4].replace(" ", "").parse::<i64>()?,
-        data[5].replace(" ", "").parse::<i64>()?,
-        data[6].replace(" ", "").parse::<i64>()?,
-        data[7].replace(" ", "").parse::<i64>()?,
-        data[8].replace(" ", "").parse::<i64>()?
-    ]);
-
-    match res {
-        Ok(1) => Ok(()),
-        _ => Err(MyError::E2()),
-    }
+#[derive(Clap)]
+struct LatencyOpts {
+    /// Database to read
+    database: String,
+    /// Name of an experiment to report on (can be repeated), defaults to all
+    #[clap(long = "experiment")]
+    experiments: Vec<String>,
+    /// Print the histogram of the latencies, with bins of this width in
+    /// milliseconds
+    #[clap(long, conflicts_with = "series", parse(try_from_str = parse_milliseconds))]
+    histogram: Option<i64>,
+    /// Print the statistics over time windows of this width in seconds
+    #[clap(long, parse(try_from_str = parse_seconds))]
+    series: Option<i64>,
+    /// Output format: table, csv or json
+    #[clap(long, default_value = "table")]
+    format: OutputFormat,
 }
 
-fn handle_rpl_stats(
-    transaction: &Transaction,
-    timestamp: &str,
-    node: &str,
-    data: &Vec<&str>,
-) -> Result<(), MyError> {
-    let mut stmt = transaction
-        .prepare_cached(
-            "INSERT INTO 'rpl_stats'
-        (
-            'Timestamp',
-            'Node',
-            'Packet Type',
-            'Measurement Type',
-            'RX unicast',
-            'TX unicast',
-            'RX multicast',
-            'TX multicast'
-          ) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
-        )
-        .unwrap();
-    let res = stmt.execute(params![
-        timestamp,
-        node,
-        data[1],
-        data[2],
-        data[3].replace(" ", "").parse::<i64>()?,
-        data[4].replace(" ", "").parse::<i64>()?,
-        data[5].replace(" ", "").parse::<i64>()?,
-        data[6].replace(" ", "").parse::<i64>()?
-    ]);
-
-    match res {
-        Ok(1) => Ok(()),
-        _ => Err(MyError::E2()),
-    }
+#[derive(Clap)]
+struct ChurnOpts {
+    /// Database to read
+    database: String,
+    /// Name of an experiment to report on (can be repeated), defaults to all
+    #[clap(long = "experiment")]
+    experiments: Vec<String>,
+    /// Print the time spent by each node with each of its parents
+    #[clap(long)]
+    parents: bool,
+    /// Output format: table, csv or json
+    #[clap(long, default_value = "table")]
+    format: OutputFormat,
 }
 
-fn handle_rpl_stats_dodag(
-    transaction: &Transaction,
-    timestamp: &str,
-    node: &str,
-    data: &Vec<&str>,
-) -> Result<(), MyError> {
-    let mut stmt = transaction
-        .prepare_cached(
-            "INSERT INTO 'rpl_stats_dodag'
-        (
-            'Timestamp',
-            'Node',
-            'Instance ID',
-            'IPv6 Adress',
-            'Rank',
-            'Role',
-            'Prefix Information',
-            'Trickle Interval Size Min',
-            'Trickle Interval Size Max',
-            'Trickle Redundancy Constant',
-            'Trickle Counter',
-            'Trickle TC'
-          ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
-        )
-        .unwrap();
-    let res = stmt.execute(params![
-        timestamp,
-        node,
-        data[1],
-        data[2],
-        data[3].replace(" ", "").parse::<i64>()?,
-        data[4],
-        data[5],
-        data[6].replace(" ", "").parse::<i64>()?,
-        data[7].replace(" ", "").parse::<i64>()?,
-        data[8].replace(" ", "").parse::<i64>()?,
-        data[9].replace(" ", "").parse::<i64>()?,
-        data[10].replace(" ", "").parse::<i64>()?
-    ]);
-
-    match res {
-        Ok(1) => Ok(()),
-        _ => Err(MyError::E2()),
-    }
+#[derive(Clap)]
+struct ExportOpts {
+    #[clap(subcommand)]
+    export: ExportCommand,
 }
 
-fn handle_rpl_stats_instance(
-    transaction: &Transaction,
-    timestamp: &str,
-    node: &str,
-    data: &Vec<&str>,
-) -> Result<(), MyError> {
-    let mut stmt = transaction
-        .prepare_cached(
-            "INSERT INTO 'rpl_stats_instance'
-        (
-            'Timestamp',
-            'Node',
-            'Instance ID',
-            'Interface ID',
-            'Mode of Operation',
-            'Objective Code Point',
-            'Min Hop Rank Increase',
-            'Max Rank Increase'
-          ) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
-        )
-        .unwrap();
-    let res = stmt.execute(params![
-        timestamp, node, data[1], data[2], data[3], data[4], data[5], data[6]
-    ]);
-
-    match res {
-        Ok(1) => Ok(()),
-        _ => Err(MyError::E2()),
-    }
+#[derive(Clap)]
+enum ExportCommand {
+    /// The RPL DODAG at a given instant, as a graph
+    Topology(TopologyOpts),
 }
 
-fn handle_rpl_stats_parent(
-    transaction: &Transaction,
-    timestamp: &str,
-    node: &str,
-    data: &Vec<&str>,
-) -> Result<(), MyError> {
-    let mut stmt = transaction
-        .prepare_cached(
-            "INSERT INTO 'rpl_stats_parent'
-        (
-            'Timestamp',
-            'Node',
-            'Instance ID',
-            'IPv6 Adress',
-            'Rank'
-          ) VALUES (?, ?, ?, ?, ?)",
-        )
-        .unwrap();
-    let res = stmt.execute(params![timestamp, node, data[1], data[2], data[3]]);
-
-    match res {
-        Ok(1) => Ok(()),
-        Ok(n) => {
-            println!("Inserting multiple ?!: {}", n);
-            Ok(())
-        }
-        Err(e) => {
-            println!("Error: {:?}", e);
-            Err(MyError::E2())
-        }
-    }
+#[derive(Clap)]
+struct TopologyOpts {
+    /// Database to read
+    database: String,
+    /// Instant of the snapshot: epoch seconds, or a UTC date such as
+    /// "2021-02-03 09:48:30"
+    #[clap(long, parse(try_from_str = parse_instant))]
+    at: i64,
+    /// Name of an experiment to export (can be repeated), defaults to all
+    #[clap(long = "experiment")]
+    experiments: Vec<String>,
+    /// Output format: dot or graphml
+    #[clap(long, default_value = "dot")]
+    format: GraphFormat,
+    /// File to write, defaults to the standard output
+    #[clap(long, short)]
+    output: Option<String>,
 }
 
-fn handle_rpl_status(
-    transaction: &Transaction,
-    timestamp: &str,
-    node: &str,
-    data: &Vec<&str>,
-) -> Result<(), MyError> {
-    let mut stmt = transaction
-        .prepare_cached(
-            "INSERT INTO 'rpl_status'
-        (
-            'Timestamp',
-            'Node',
-            'Type of table',
-            'Index of the table',
-            'Table status'
-          ) VALUES (?, ?, ?, ?, ?)",
-        )
-        .unwrap();
-    let res = stmt.execute(params![timestamp, node, data[1], data[2], data[3]]);
+fn parse_parameter(parameter: &str) -> std::result::Result<(String, String), String> {
+    match parameter.find('=') {
+        Some(position) => Ok((
+            parameter[..position].to_string(),
+            parameter[position + 1..].to_string(),
+        )),
+        None => Err(format!("expected key=value, got '{}'", parameter)),
+    }
+}
 
-    match res {
-        Ok(1) => Ok(()),
-        _ => Err(MyError::E2()),
+fn parse_positive<T>(value: &str) -> std::result::Result<T, String>
+where
+    T: std::str::FromStr + PartialOrd + Default,
+    T::Err: std::fmt::Display,
+{
+    match value.parse::<T>() {
+        Ok(v) if v > T::default() => Ok(v),
+        Ok(_) => Err(format!("expected a positive number, got '{}'", value)),
+        Err(e) => Err(e.to_string()),
     }
 }
 
-fn handle_stats(
-    transaction: &Transaction,
-    timestamp: &str,
-    node: &str,
-    data: &Vec<&str>,
-) -> Result<(), MyError> {
-    let mut stmt = transaction
-        .prepare_cached(
-            "INSERT INTO 'stats'
-        (
-            'Timestamp',
-            'Node',
-            'layer',
-            'rx packets',
-            'rx bytes',
-            'tx packets',
-            'tx multicast packets',
-            'tx bytes',
-            'tx succeeded',
-            'tx errors'
-          ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
-        )
-        .unwrap();
-    let res = stmt.execute(params![
-        timestamp,
-        node,
-        data[1].replace(" ", "").parse::<i64>()?,
-        data[2],
-        data[3].replace(" ", "").parse::<i64>()?,
-        data[4].replace(" ", "").parse::<i64>()?,
-        data[5].replace(" ", "").parse::<i64>()?,
-        data[6].replace(" ", "").parse::<i64>()?,
-        data[7].replace(" ", "").parse::<i64>()?,
-        data[8].replace(" ", "").parse::<i64>()?,
-    ]);
-
-    match res {
-        Ok(1) => Ok(()),
-        _ => Err(MyError::E2()),
+/// Parses a positive duration in milliseconds into nanoseconds
+fn parse_milliseconds(value: &str) -> std::result::Result<i64, String> {
+    let milliseconds: f64 = parse_positive(value)?;
+    let nanoseconds = (milliseconds * 1e6).round();
+    if nanoseconds < 1.0 || nanoseconds >= i64::MAX as f64 {
+        return Err(format!(
+            "expected a duration between 1 ns and {} ms, got '{}'",
+            i64::MAX / 1_000_000,
+            value
+        ));
     }
+    Ok(nanoseconds as i64)
 }
 
-fn handle_udp(
-    transaction: &Transaction,
-    timestamp: &str,
-    node: &str,
-    data: &Vec<&str>,
-) -> Result<(), MyError> {
-    let mut stmt = transaction
-        .prepare_cached(
-            "INSERT INTO 'udp'
-        (
-            'Timestamp',
-            'Node',
-            'payload size',
-            'destination address',
-            'destination port',
-            'payload'
-          ) VALUES (?, ?, ?, ?, ?, ?)",
+/// Parses a positive duration in seconds into nanoseconds
+fn parse_seconds(value: &str) -> std::result::Result<i64, String> {
+    let seconds: i64 = parse_positive(value)?;
+    seconds.checked_mul(1_000_000_000).ok_or_else(|| {
+        format!(
+            "expected at most {} seconds, got '{}'",
+            i64::MAX / 1_000_000_000,
+            value
         )
-        .unwrap();
-    let res = stmt.execute(params![timestamp, node, data[1], data[2], data[3], data[4]]);
+    })
+}
 
-    match res {
-        Ok(1) => Ok(()),
-        _ => Err(MyError::E2()),
+/// Parses an instant into nanoseconds since the epoch
+fn parse_instant(instant: &str) -> std::result::Result<i64, String> {
+    let nanoseconds = |timestamp: NaiveDateTime| {
+        timestamp
+            .timestamp()
+            .checked_mul(1_000_000_000)
+            .and_then(|ns| ns.checked_add(i64::from(timestamp.timestamp_subsec_nanos())))
+            .ok_or_else(|| format!("instant out of range, got '{}'", instant))
+    };
+    if let Ok(timestamp) = parse_timestamp(instant) {
+        return Ok(timestamp.timestamp_nanos());
+    }
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(instant) {
+        return nanoseconds(timestamp.naive_utc());
     }
+    let date = instant.trim().trim_end_matches(" UTC");
+    NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S%.f")
+        .map_err(|_| format!("expected epoch seconds or a date, got '{}'", instant))
+        .and_then(nanoseconds)
 }
 
-fn handle_server(
-    transaction: &Transaction,
-    timestamp: &str,
-    ipv6: &str,
-    port: &str,
-    payload: &str,
-) -> Result<(), MyError> {
-    let mut stmt = transaction
-        .prepare_cached(
-            "INSERT INTO 'server'
-        (
-            'Timestamp',
-            'IPv6 Adress',
-            'receiver port',
-            'payload'
-          ) VALUES (?, ?, ?, ?)",
-        )
-        .unwrap();
-    let res = stmt.execute(params![timestamp, ipv6, port.parse::<i64>()?, payload]);
-    match res {
-        Ok(1) => Ok(()),
-        _ => Err(MyError::E2()),
-    }
+fn load_schema(path: &Option<String>) -> Result<Arc<Schema>> {
+    Ok(Arc::new(match path {
+        Some(path) => {
+            println!("Using schema file: {}", path);
+            Schema::from_file(path)?
+        }
+        None => Schema::default(),
+    }))
 }
 
-fn handle_output(
-    transaction: &Transaction,
-    timestamp: &str,
-    node: &str,
-    stdout: &str,
-) -> Result<(), MyError> {
-    let mut stmt = transaction
-        .prepare_cached(
-            "INSERT INTO 'output'
-        (
-            'Timestamp',
-            'Node',
-            'Output Stdout'
-          ) VALUES (?, ?, ?)",
-        )
-        .unwrap();
-    let res = stmt.execute(params![timestamp, node, stdout]);
-    match res {
-        Ok(1) => Ok(()),
-        _ => Err(MyError::E2()),
-    }
+fn analysis_options(opts: &Opts) -> AnalysisOptions {
+    AnalysisOptions { drift: opts.drift }
 }
 
-fn handle_info(
-    transaction: &Transaction,
-    timestamp: &str,
-    node: &str,
-    message: &str,
-) -> Result<(), MyError> {
-    let mut stmt = transaction
-        .prepare_cached(
-            "INSERT INTO 'info'
-        (
-            'Timestamp',
-            'Node',
-            'Message'
-          ) VALUES (?, ?, ?)",
-        )
-        .unwrap();
-    let res = stmt.execute(params![timestamp, node, message]);
-    match res {
-        Ok(1) => Ok(()),
-        _ => Err(MyError::E2()),
+/// Ingests one experiment into the database at `output`
+fn run_single(opts: &Opts, output: &str, input_serial: &str, input_server: &str) -> Result<Status> {
+    println!("Using serial file: {}", input_serial);
+    println!("Using server file: {}", input_server);
+
+    let schema = load_schema(&opts.schema)?;
+
+    let name = match &opts.name {
+        Some(name) => name.clone(),
+        None => Path::new(input_serial)
+            .file_stem()
+            .map_or(input_serial.to_string(), |stem| {
+                stem.to_string_lossy().to_string()
+            }),
+    };
+    let mut experiment = Experiment::new(&name, input_serial, input_server);
+    experiment.parameters = opts.parameters.clone();
+
+    /* The serial and server files can be parsed concurrently */
+    let mut status = Status::Complete;
+    for (_, result) in ingest_shared(output, &schema, vec![experiment], opts.jobs, opts.recover)? {
+        status = status.and(result?);
+    }
+    if let Some(e) =
+        warn_failed_analyses(analyze_database(output, &[name], &analysis_options(opts))?)
+    {
+        return Err(e);
     }
+    Ok(status)
 }
 
-fn dispatch(
-    transaction: &Transaction,
-    timestamp: &str,
-    node: &str,
-    splitted: Vec<&str>,
-) -> std::result::Result<(), MyError> {
-    let tag = splitted[0];
-    let epoch: f64 = timestamp.parse()?;
-    let timestamp = NaiveDateTime::from_timestamp(epoch.trunc() as i64, (1e9 * epoch.fract()) as u32);
-    let timestamp: DateTime<Utc> = DateTime::from_utc(timestamp, Utc);
-    let timestamp = timestamp.to_string();
-    match tag {
-        "neighbor_stats" => handle_neighbor_stats(transaction, &timestamp, node, &splitted),
-        "rpl_stats" => handle_rpl_stats(transaction, &timestamp, node, &splitted),
-        "rpl_stats_dodag" => handle_rpl_stats_dodag(transaction, &timestamp, node, &splitted),
-        "rpl_stats_instance" => handle_rpl_stats_instance(transaction, &timestamp, node, &splitted),
-        "rpl_stats_parent" => handle_rpl_stats_parent(transaction, &timestamp, node, &splitted),
-        "rpl_status" => handle_rpl_status(transaction, &timestamp, node, &splitted),
-        "stats" => handle_stats(transaction, &timestamp, node, &splitted),
-        "udp" => handle_udp(transaction, &timestamp, node, &splitted),
-        _ => {
-            println!("Unknown tag: {}", tag);
-            Ok(())
+/// Warns about the experiments whose analysis failed, and returns the first error
+fn warn_failed_analyses(outcomes: AnalysisOutcomes) -> Option<moulinette::Error> {
+    let mut first = None;
+    for (name, result) in outcomes {
+        if let Err(e) = result {
+            println!("Warning: analysis of experiment {} failed: {}", name, e);
+            first.get_or_insert(e);
         }
     }
+    first
 }
 
-fn setup_database(connection: &Connection) {
-    /* Create the tables */
-    connection
-        .execute_batch(
-            r#"
-    CREATE TABLE IF NOT EXISTS "info" (
-      "Timestamp" TEXT,
-      "Node" TEXT,
-      "Message" TEXT
-    );
-
-    CREATE TABLE IF NOT EXISTS "neighbor_stats" (
-      "Timestamp" TEXT,
-      "Node" TEXT,
-      "L2 address" TEXT,
-      "fresh" INTEGER,
-      "etx" TEXT,
-      "sent" INTEGER,
-      "received" INTEGER,
-      "rssi (dBm)" INTEGER,
-      "lqi" INTEGER,
-      "avg tx time (µs)" INTEGER
+/// Ingests every experiment found under the campaign directory, skipping
+/// the ones which cannot be paired or which fail
+fn run_campaign(opts: &Opts, campaign_opts: &CampaignOpts) -> Result<Status> {
+    let schema = load_schema(&opts.schema)?;
+    let mut campaign = Campaign::discover(Path::new(&campaign_opts.directory))?;
+    println!(
+        "Found {} experiments in {}",
+        campaign.experiments.len(),
+        campaign_opts.directory
     );
-
-    CREATE TABLE IF NOT EXISTS "output" (
-      "Timestamp" TEXT,
-      "Node" TEXT,
-      "Output Stdout" TEXT
-    );
-
-    CREATE TABLE IF NOT EXISTS "rpl_stats" (
-      "Timestamp" TEXT,
-      "Node" TEXT,
-      "Packet Type" TEXT,
-      "Measurement Type" TEXT,
-      "RX unicast" INTEGER,
-      "TX unicast" INTEGER,
-      "RX multicast" INTEGER,
-      "TX multicast" INTEGER
-    );
-
-    CREATE TABLE IF NOT EXISTS "rpl_stats_dodag" (
-      "Timestamp" TEXT,
-      "Node" TEXT,
-      "Instance ID" TEXT,
-      "IPv6 Adress" TEXT,
-      "Rank" TEXT,
-      "Role" TEXT,
-      "Prefix Information" TEXT,
-      "Trickle Interval Size Min" INTEGER,
-      "Trickle Interval Size Max" INTEGER,
-      "Trickle Redundancy Constant" INTEGER,
-      "Trickle Counter" INTEGER,
-      "Trickle TC" INTEGER
-    );
-
-    CREATE TABLE IF NOT EXISTS "rpl_stats_instance" (
-        "Timestamp" TEXT,
-        "Node" TEXT,
-        "Instance ID" TEXT,
-        "Interface ID" TEXT,
-        "Mode of Operation" TEXT,
-        "Objective Code Point" TEXT,
-        "Min Hop Rank Increase" TEXT,
-        "Max Rank Increase" TEXT
-      );
-
-    CREATE TABLE IF NOT EXISTS "rpl_stats_parent" (
-        "Timestamp" TEXT,
-        "Node" TEXT,
-        "Instance ID" TEXT,
-        "IPv6 Adress" TEXT,
-        "Rank" TEXT
-    );
-
-    CREATE TABLE IF NOT EXISTS "rpl_status" (
-      "Timestamp" TEXT,
-      "Node" TEXT,
-      "Type of table" TEXT,
-      "Index of the table" TEXT,
-      "Table status" TEXT
-    );
-
-    CREATE TABLE IF NOT EXISTS "stats" (
-      "Timestamp" TEXT,
-      "Node" TEXT,
-      "success" INTEGER,
-      "layer" TEXT,
-      "rx packets" INTEGER,
-      "rx bytes" INTEGER,
-      "tx packets" INTEGER,
-      "tx multicast packets" INTEGER,
-      "tx bytes" INTEGER,
-      "tx succeeded" INTEGER,
-      "tx errors" INTEGER
-    );
-
-    CREATE TABLE IF NOT EXISTS "udp" (
-        "Timestamp" TEXT,
-        "Node" TEXT,
-        "payload size" TEXT,
-        "destination address" TEXT,
-        "destination port" INTEGER,
-        "payload" TEXT
-      );
-      
-      CREATE TABLE IF NOT EXISTS "server" (
-        "Timestamp" TEXT,
-        "IPv6 Adress" TEXT,
-        "receiver port" INTEGER,
-        "payload" TEXT
-      );
-      "#,
+    for experiment in &mut campaign.experiments {
+        experiment.parameters = opts.parameters.clone();
+    }
+    let experiments = std::mem::take(&mut campaign.experiments);
+    let split_path = |name: &str| {
+        Path::new(&campaign_opts.output)
+            .join(format!("{}.db", name.replace('/', "_")))
+            .to_string_lossy()
+            .to_string()
+    };
+
+    let outcomes = if campaign_opts.split {
+        std::fs::create_dir_all(&campaign_opts.output)
+            .map_err(|e| moulinette::Error::from(e).in_file(&campaign_opts.output))?;
+        ingest_split(
+            &schema,
+            experiments,
+            opts.jobs,
+            opts.recover,
+            |experiment| split_path(&experiment.name),
         )
-        .unwrap();
-}
-
-fn handle_serial_data(connection: &mut Connection, filename: String) -> Result<(), MyError> {
-    /* Used to detect whether this is the header line for the node output */
-    let mut node_headers: HashSet<(String, String)> = HashSet::new();
-
-    /* Actual header mapping */
-    let mut headers: HashMap<String, Vec<String>> = HashMap::new();
-
-    let f = File::open(filename)?;
-    let chunk_size = 100_000_000;
-    let mut decoder = zstd::stream::Decoder::new(f)?;
-    let mut buffer = Vec::with_capacity(chunk_size);
-    let mut leftover: String = String::new();
-
-    headers.insert("info".to_string(), vec!["Message".to_string()]);
-    headers.insert(
-        "rpl_stats_parent".to_string(),
-        vec![
-            "Instance ID".to_string(),
-            "IPv6 Adress".to_string(),
-            "Rank".to_string(),
-        ],
-    );
-
-    let mut do_break = false;
-    loop {
-        println!("Reading new chunk.");
-        /* We clear the previous data */
-        buffer.clear();
-        println!("New transaction");
-        let t = connection.transaction().unwrap();
-
-        /* We read at most chunk_size bytes and put them into the buffer */
-        let n = decoder
-            .by_ref()
-            .take(chunk_size as u64)
-            .read_to_end(&mut buffer)?;
-        match n {
-            0 => {
-                /* No data left ! We just handle the remaining data. */
-                match leftover.as_str() {
-                    "" => {
-                        println!("No data left.");
-                        break;
-                    }
-                    _ => {
-                        println!("Handling remaining data: {}", leftover);
-                        do_break = true;
-                    }
-                }
-            }
-            _ => {
-                println!("{} bytes read.", n);
-                leftover += std::str::from_utf8(&buffer).unwrap();
+    } else {
+        ingest_shared(
+            &campaign_opts.output,
+            &schema,
+            experiments,
+            opts.jobs,
+            opts.recover,
+        )?
+    };
+
+    let (mut ingested, mut partial) = (Vec::new(), Vec::new());
+    for (experiment, result) in outcomes {
+        match result {
+            Ok(Status::Complete) => ingested.push(experiment.name),
+            Ok(Status::Partial) => {
+                partial.push(experiment.name.clone());
+                ingested.push(experiment.name);
             }
-        };
-
-        /* Find the last line */
-        let last_line_pos = leftover.rfind('\n').unwrap();
-
-        /* Only process full lines */
-        let lines = (&leftover[0..last_line_pos]).lines();
-
-        /* Iterate over the lines */
-        for line in lines {
-            let splitted: Vec<&str> = line.split(";").collect();
-            let timestamp = splitted[0];
-            let node = splitted[1];
-            let data = splitted[2];
-            let nested_splitted: Vec<&str> = data.split(",").collect();
-            if nested_splitted.len() > 1 {
-                /* Formatted Data */
-                let line_type = nested_splitted[0].to_string();
-
-                match (
-                    &line_type[..],
-                    node_headers.contains(&(node.to_string(), line_type.to_string())),
-                ) {
-                    ("info", true) => {
-                        handle_info(&t, &timestamp, &node, &data)?;
-                    }
-                    (_, true) => {
-                        /* It's data and it's not the first line: we add it to the database */
-                        match dispatch(&t, &timestamp, &node, nested_splitted) {
-                            Err(e) => {
-                                println!("Error parsing line {}: {:?}", line, e);
-                                continue;
-                            }
-                            _ => {}
-                        }
-                    }
-                    ("info", false) => {
-                        /* We manually add the header because we print it too early so the serial aggregator does not have time to read it */
-                        node_headers.insert((node.to_string(), "info".to_string()));
-                        handle_info(&t, &timestamp, &node, &data)?;
-                    }
-                    (line_type, false) => {
-                        /* It's the first line: we add it as an header */
-                        node_headers.insert((node.to_string(), line_type.to_string()));
-
-                        /* Check wheter we already have seen this kind of data */
-                        match headers.get(&(line_type.to_string())) {
-                            None => {
-                                headers.insert(
-                                    line_type.to_string(),
-                                    nested_splitted[1..].iter().map(|s| s.to_string()).collect(),
-                                );
-                            }
-                            Some(_) => { /* The line already exists */ }
-                        }
-                    }
+            /* A failing experiment must not prevent the others from being ingested */
+            Err(e) => {
+                if !matches!(e.kind, ErrorKind::ExperimentExists(_)) {
+                    println!("Warning: experiment {} failed: {}", experiment.name, e);
                 }
-            } else {
-                handle_output(&t, &timestamp, &node, &data)?;
+                campaign.skipped.push(moulinette::Skipped {
+                    path: Path::new(&experiment.serial_file)
+                        .parent()
+                        .map_or_else(Default::default, |p| p.to_path_buf()),
+                    reason: e.to_string(),
+                });
             }
         }
-        /* We commit the data to the database */
-        match t.commit() {
-            Err(e) => {
-                println!("Error commiting: {:?}", e);
+    }
+
+    /* Like the ingestion, the analysis of an experiment must not prevent the others' */
+    let options = analysis_options(opts);
+    let analyzed = if campaign_opts.split {
+        ingested
+            .iter()
+            .map(|name| analyze_database(&split_path(name), std::slice::from_ref(name), &options))
+            .collect()
+    } else if ingested.is_empty() {
+        /* No names would mean all the experiments of the database */
+        Vec::new()
+    } else {
+        vec![analyze_database(&campaign_opts.output, &ingested, &options)]
+    };
+    for result in analyzed {
+        match result {
+            Ok(outcomes) => {
+                warn_failed_analyses(outcomes);
             }
-            _ => {
-                println!("Commit successful.");
-            } //thread 'main' panicked at 'called `Result::unwrap()` on an `Err` value: SqliteFailure(Error { code: Unknown, extended_code: 1 }, Some("cannot commit - no transaction is active"))', src/main.rs:614:20
+            Err(e) => println!("Warning: analysis failed: {}", e),
         }
+    }
 
-        /* We skip the last new line */
-        leftover = leftover[(last_line_pos + 1)..].to_string();
+    println!(
+        "{} experiments ingested, {} skipped.",
+        ingested.len(),
+        campaign.skipped.len()
+    );
+    for skipped in &campaign.skipped {
+        println!("Skipped {}: {}", skipped.path.display(), skipped.reason);
+    }
+    for name in &partial {
+        println!("Partially ingested {}: truncated input", name);
+    }
+    Ok(if partial.is_empty() {
+        Status::Complete
+    } else {
+        Status::Partial
+    })
+}
 
-        if do_break {
-            println!("End of parsing. Leftover: {}", leftover);
-            break;
+/// Prints the requested report on standard output, and its warnings on
+/// standard error so that the output can be piped
+fn run_report(report_opts: &ReportOpts) -> Result<Status> {
+    let (database, format, report) = match &report_opts.report {
+        ReportCommand::Pdr(pdr_opts) => {
+            let connection = open_database(&pdr_opts.database)?;
+            let report = pdr(&connection, &pdr_opts.experiments, pdr_opts.window)?;
+            (&pdr_opts.database, pdr_opts.format, report)
         }
+        ReportCommand::Latency(latency_opts) => {
+            let connection = open_database(&latency_opts.database)?;
+            let names = &latency_opts.experiments;
+            let report = match (latency_opts.histogram, latency_opts.series) {
+                (Some(bin), _) => latency_histogram(&connection, names, bin)?,
+                (None, Some(window)) => latency_series(&connection, names, window)?,
+                (None, None) => latency(&connection, names)?,
+            };
+            (&latency_opts.database, latency_opts.format, report)
+        }
+        ReportCommand::Churn(churn_opts) => {
+            let connection = open_database(&churn_opts.database)?;
+            let report = if churn_opts.parents {
+                parent_durations(&connection, &churn_opts.experiments)?
+            } else {
+                churn(&connection, &churn_opts.experiments)?
+            };
+            (&churn_opts.database, churn_opts.format, report)
+        }
+    };
+    if report.rows.is_empty() {
+        eprintln!("Warning: nothing to report, was {} analyzed?", database);
     }
-
-    Ok(())
+    report.write(format, &mut std::io::stdout().lock())?;
+    Ok(Status::Complete)
 }
 
-fn handle_server_data(connection: &mut Connection, filename: String) -> Result<(), MyError> {
-    let f = File::open(filename)?;
-
-    let chunk_size = 100_000_000;
-    let mut decoder = zstd::stream::Decoder::new(f)?;
-    let mut buffer = Vec::with_capacity(chunk_size);
-    let mut leftover: String = String::new();
-    let mut is_header: bool = true;
-    let mut do_break = false;
-
-    loop {
-        /* We clear the previous data */
-        buffer.clear();
-        let t = connection.transaction().unwrap();
-
-        /* We read at most chunk_size bytes and put them into the buffer */
-        let n = decoder
-            .by_ref()
-            .take(chunk_size as u64)
-            .read_to_end(&mut buffer)?;
-        match n {
-            0 => {
-                /* No data left ! We just handle the remaining data. */
-                match leftover.as_str() {
-                    "" => {
-                        t.commit().unwrap();
-                        break;
-                    }
-                    _ => {
-                        do_break = true;
-                    }
-                }
-            }
-            _ => {
-                leftover += std::str::from_utf8(&buffer).unwrap();
-            }
-        };
-
-        /* Find the last line */
-        let last_line_pos = leftover.rfind('\n').unwrap();
-
-        /* Only process full lines */
-        let lines = (&leftover[0..last_line_pos]).lines();
+/// Writes the requested export to its output file, or to standard output
+fn run_export(export_opts: &ExportOpts) -> Result<Status> {
+    match &export_opts.export {
+        ExportCommand::Topology(topology_opts) => {
+            let connection = open_database(&topology_opts.database)?;
+            let mut out: Box<dyn std::io::Write> = match &topology_opts.output {
+                Some(path) => Box::new(
+                    std::fs::File::create(path)
+                        .map_err(|e| moulinette::Error::from(e).in_file(path))?,
+                ),
+                None => Box::new(std::io::stdout()),
+            };
+            export_topology(
+                &connection,
+                &topology_opts.experiments,
+                topology_opts.at,
+                topology_opts.format,
+                &mut out,
+            )?;
+        }
+    }
+    Ok(Status::Complete)
+}
 
-        /* Iterate over the lines */
-        for line in lines {
-            if is_header {
-                is_header = false;
-                continue;
+fn run(opts: Opts) -> Result<Status> {
+    match (
+        &opts.command,
+        &opts.output,
+        &opts.input_serial,
+        &opts.input_server,
+    ) {
+        (Some(Command::Campaign(campaign_opts)), _, _, _) => run_campaign(&opts, campaign_opts),
+        (Some(Command::Analyze(analyze_opts)), _, _, _) => {
+            let outcomes = analyze_database(
+                &analyze_opts.database,
+                &analyze_opts.experiments,
+                &analysis_options(&opts),
+            )?;
+            match warn_failed_analyses(outcomes) {
+                Some(e) => Err(e),
+                None => Ok(Status::Complete),
             }
-            let splitted: Vec<&str> = line.split(",").collect();
-            let timestamp = splitted[0].replace(' ', "T");
-            let timestamp = DateTime::parse_from_rfc3339(&timestamp);
-            let timestamp: DateTime<Utc> = DateTime::from_utc(timestamp?.naive_utc(), Utc);
-            let timestamp = timestamp.to_string();
-            let ipv6 = splitted[1];
-            let port = splitted[2];
-            let payload = splitted[3];
-            handle_server(&t, &timestamp, ipv6, port, payload)?;
         }
-        /* We commit the data to the database */
-        t.commit().unwrap();
-        /* We skip the last new line */
-        leftover = leftover[(last_line_pos + 1)..].to_string();
-
-        if do_break {
-            println!("End of parsing. Leftover: {}", leftover);
-            break;
+        (Some(Command::Report(report_opts)), _, _, _) => run_report(report_opts),
+        (Some(Command::Export(export_opts)), _, _, _) => run_export(export_opts),
+        (None, Some(output), Some(input_serial), Some(input_server)) => {
+            run_single(&opts, output, input_serial, input_server)
         }
+        /* Enforced by clap */
+        _ => unreachable!(),
     }
-    Ok(())
 }
 
-fn main() -> Result<(), MyError> {
+fn main() {
     let opts: Opts = Opts::parse();
-    println!("Using serial file: {}", opts.input_serial);
-    println!("Using server file: {}", opts.input_server);
-
-    /* Connect to the database */
-    let mut conn = Connection::open(opts.output).unwrap();
-
-    /* Create the tables if needed */
-    setup_database(&conn);
-
-    println!("Parsing serial data.");
-    handle_serial_data(&mut conn, opts.input_serial)?;
-    println!("Parsing server data");
-    handle_server_data(&mut conn, opts.input_server)?;
-    println!("Vacuuming");
-    conn.execute_batch(
-        r#"VACUUM;
-        "#,
-    )?;
-    Ok(())
+    match run(opts) {
+        Ok(Status::Complete) => {}
+        Ok(Status::Partial) => {
+            eprintln!("Warning: some inputs were truncated, only their readable part was ingested");
+            std::process::exit(2);
+        }
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            if let ErrorKind::Truncated(_) = e.kind {
+                eprintln!("Use --recover to keep the data decoded before the break");
+            }
+            std::process::exit(1);
+        }
+    }
 }
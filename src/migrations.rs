@@ -0,0 +1,443 @@
+use crate::error::{Error, ErrorKind, Result};
+use crate::sqlite::{quote, timestamp_index};
+use rusqlite::Connection;
+
+/// A step upgrading the database from the previous version to the next one
+type Migration = fn(&Connection) -> Result<()>;
+
+/// The migrations, in order: the database is at version `n` once the first
+/// `n` migrations have been applied.
+///
+/// The tables of the serial tags are not created here: they come from the
+/// schema, which always describes the latest layout, and are created once
+/// the database is up to date. Migrations touching them must therefore only
+/// alter the tables which already exist.
+const MIGRATIONS: &[Migration] = &[
+    create_base_tables,
+    add_experiments,
+    add_raw_bytes,
+    add_timestamp_nanoseconds,
+    add_clock_alignment,
+    add_deliveries,
+    add_latency_summary,
+    add_topology_edges,
+    add_parent_changes,
+    add_reboots,
+];
+
+/// The version of the databases written by this version of moulinette
+pub const VERSION: i64 = MIGRATIONS.len() as i64;
+
+/// Version 1: the server and rejects tables
+fn create_base_tables(connection: &Connection) -> Result<()> {
+    connection.execute_batch(
+        r#"
+      CREATE TABLE IF NOT EXISTS "server" (
+        "Timestamp" TEXT,
+        "IPv6 Adress" TEXT,
+        "receiver port" INTEGER,
+        "payload" TEXT
+      );
+
+      CREATE TABLE IF NOT EXISTS "rejects" (
+        "Source File" TEXT,
+        "Line Number" INTEGER,
+        "Byte Offset" INTEGER,
+        "Node" TEXT,
+        "Tag" TEXT,
+        "Raw Line" TEXT,
+        "Error Kind" TEXT,
+        "Error Message" TEXT
+      );
+      "#,
+    )?;
+    Ok(())
+}
+
+/// The names of the tables holding records, i.e. every table but `experiments`
+fn data_tables(connection: &Connection) -> Result<Vec<String>> {
+    let names = connection
+        .prepare(
+            "SELECT name FROM sqlite_master
+             WHERE type = 'table' AND name != 'experiments' AND name NOT LIKE 'sqlite_%'",
+        )?
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(names)
+}
+
+/// Version 2: the experiments table, and the experiment of each record
+fn add_experiments(connection: &Connection) -> Result<()> {
+    connection.execute_batch(
+        r#"
+      CREATE TABLE IF NOT EXISTS "experiments" (
+        "id" INTEGER PRIMARY KEY,
+        "Name" TEXT NOT NULL UNIQUE,
+        "Start" TEXT,
+        "End" TEXT,
+        "Serial File" TEXT,
+        "Server File" TEXT,
+        "Parameters" TEXT
+      );
+      "#,
+    )?;
+    for table in data_tables(connection)? {
+        connection.execute_batch(&format!(
+            "ALTER TABLE {} ADD COLUMN \"experiment_id\" INTEGER;",
+            quote(&table)
+        ))?;
+    }
+    Ok(())
+}
+
+/// Version 3: the raw bytes of the rejected lines which are not valid UTF-8
+fn add_raw_bytes(connection: &Connection) -> Result<()> {
+    connection.execute_batch(r#"ALTER TABLE "rejects" ADD COLUMN "Raw Bytes" BLOB;"#)?;
+    Ok(())
+}
+
+/// The columns of `table`
+pub(crate) fn columns(connection: &Connection, table: &str) -> Result<Vec<String>> {
+    let columns = connection
+        .prepare(&format!("PRAGMA table_info({})", quote(table)))?
+        .query_map([], |row| row.get(1))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(columns)
+}
+
+/// Version 4: the timestamps as integer nanoseconds since the epoch, indexed.
+///
+/// They are computed from the text timestamps, written either as
+/// `2021-02-03 09:47:59.000123023 UTC` or, by older versions, as the raw
+/// epoch printed by the serial aggregator.
+fn add_timestamp_nanoseconds(connection: &Connection) -> Result<()> {
+    for table in data_tables(connection)? {
+        if !columns(connection, &table)?
+            .iter()
+            .any(|c| c == "Timestamp")
+        {
+            continue;
+        }
+        connection.execute_batch(&format!(
+            r#"
+          ALTER TABLE {table} ADD COLUMN "ts_ns" INTEGER;
+          UPDATE {table} SET "ts_ns" = CASE
+            WHEN "Timestamp" LIKE '____-__-__ __:__:__%' THEN
+              CAST(strftime('%s', substr("Timestamp", 1, 19)) AS INTEGER) * 1000000000
+              + CASE WHEN substr("Timestamp", 20, 1) = '.'
+                  THEN CAST(substr(rtrim(substr("Timestamp", 21), ' UTC') || '000000000', 1, 9) AS INTEGER)
+                  ELSE 0 END
+            WHEN "Timestamp" GLOB '[0-9]*.[0-9]*' THEN
+              CAST(substr("Timestamp", 1, instr("Timestamp", '.') - 1) AS INTEGER) * 1000000000
+              + CAST(substr(substr("Timestamp", instr("Timestamp", '.') + 1) || '000000000', 1, 9) AS INTEGER)
+            WHEN "Timestamp" GLOB '[0-9]*' THEN CAST("Timestamp" AS INTEGER) * 1000000000
+          END;
+          {index}
+          "#,
+            table = quote(&table),
+            index = timestamp_index(&table)
+        ))?;
+    }
+    Ok(())
+}
+
+/// Version 5: the clock alignment of the server, and its aligned timestamps
+fn add_clock_alignment(connection: &Connection) -> Result<()> {
+    connection.execute_batch(
+        r#"
+      CREATE TABLE IF NOT EXISTS "clock_fit" (
+        "experiment_id" INTEGER PRIMARY KEY,
+        "offset_ns" INTEGER,
+        "drift_ppm" REAL,
+        "reference_ts_ns" INTEGER,
+        "pairs" INTEGER,
+        "windows" INTEGER,
+        "residual_ns" REAL
+      );
+
+      ALTER TABLE "server" ADD COLUMN "aligned_ts_ns" INTEGER;
+      CREATE INDEX IF NOT EXISTS "server_payload" ON "server" ("experiment_id", "payload");
+      "#,
+    )?;
+    Ok(())
+}
+
+/// Version 6: the udp packets matched with their receptions by the server
+fn add_deliveries(connection: &Connection) -> Result<()> {
+    connection.execute_batch(
+        r#"
+      CREATE TABLE IF NOT EXISTS "deliveries" (
+        "experiment_id" INTEGER,
+        "node" TEXT,
+        "payload" TEXT,
+        "send_ts_ns" INTEGER,
+        "receive_ts_ns" INTEGER,
+        "latency_ns" INTEGER,
+        "delivered" INTEGER,
+        "receptions" INTEGER,
+        "source_address" TEXT
+      );
+      CREATE INDEX IF NOT EXISTS "deliveries_node" ON "deliveries" ("experiment_id", "node", "send_ts_ns");
+      "#,
+    )?;
+    Ok(())
+}
+
+/// Version 7: the latency statistics of the delivered packets
+fn add_latency_summary(connection: &Connection) -> Result<()> {
+    connection.execute_batch(
+        r#"
+      CREATE TABLE IF NOT EXISTS "latency_summary" (
+        "experiment_id" INTEGER,
+        "node" TEXT,
+        "packets" INTEGER,
+        "min_ns" INTEGER,
+        "mean_ns" REAL,
+        "median_ns" INTEGER,
+        "p95_ns" INTEGER,
+        "p99_ns" INTEGER,
+        "max_ns" INTEGER
+      );
+      "#,
+    )?;
+    Ok(())
+}
+
+/// Version 8: the RPL parent of each node over time
+fn add_topology_edges(connection: &Connection) -> Result<()> {
+    connection.execute_batch(
+        r#"
+      CREATE TABLE IF NOT EXISTS "topology_edges" (
+        "experiment_id" INTEGER,
+        "child" TEXT,
+        "parent" TEXT,
+        "parent_address" TEXT,
+        "instance" TEXT,
+        "valid_from" INTEGER,
+        "valid_to" INTEGER,
+        "samples" INTEGER
+      );
+      CREATE INDEX IF NOT EXISTS "topology_edges_child" ON "topology_edges" ("experiment_id", "child", "valid_from");
+      "#,
+    )?;
+    Ok(())
+}
+
+/// Version 9: the parent changes of the nodes, and their churn
+fn add_parent_changes(connection: &Connection) -> Result<()> {
+    connection.execute_batch(
+        r#"
+      CREATE TABLE IF NOT EXISTS "parent_changes" (
+        "experiment_id" INTEGER,
+        "node" TEXT,
+        "instance" TEXT,
+        "ts_ns" INTEGER,
+        "old_parent" TEXT,
+        "new_parent" TEXT,
+        "old_parent_address" TEXT,
+        "new_parent_address" TEXT,
+        "old_rank" TEXT,
+        "new_rank" TEXT
+      );
+      CREATE INDEX IF NOT EXISTS "parent_changes_node" ON "parent_changes" ("experiment_id", "node", "ts_ns");
+
+      CREATE TABLE IF NOT EXISTS "churn_summary" (
+        "experiment_id" INTEGER,
+        "node" TEXT,
+        "changes" INTEGER,
+        "observed_ns" INTEGER,
+        "changes_per_hour" REAL
+      );
+
+      CREATE TABLE IF NOT EXISTS "parent_durations" (
+        "experiment_id" INTEGER,
+        "node" TEXT,
+        "parent" TEXT,
+        "parent_address" TEXT,
+        "duration_ns" INTEGER,
+        "share" REAL
+      );
+      "#,
+    )?;
+    Ok(())
+}
+
+/// Version 10: the reboots of the nodes, and the boot of each serial row
+fn add_reboots(connection: &Connection) -> Result<()> {
+    for table in data_tables(connection)? {
+        let columns = columns(connection, &table)?;
+        if columns.iter().any(|c| c == "Node") && columns.iter().any(|c| c == "ts_ns") {
+            connection.execute_batch(&format!(
+                r#"ALTER TABLE {} ADD COLUMN "boot" INTEGER;"#,
+                quote(&table)
+            ))?;
+        }
+    }
+    connection.execute_batch(
+        r#"
+      CREATE TABLE IF NOT EXISTS "node_reboots" (
+        "Timestamp" TEXT,
+        "Node" TEXT,
+        "cause" TEXT,
+        "experiment_id" INTEGER,
+        "ts_ns" INTEGER,
+        "boot" INTEGER
+      );
+      CREATE INDEX IF NOT EXISTS "node_reboots_node" ON "node_reboots" ("experiment_id", "Node", "ts_ns");
+      "#,
+    )?;
+    Ok(())
+}
+
+/// The schema version recorded in the database, 0 for new databases and
+/// for the ones written before versioning
+pub fn version(connection: &Connection) -> Result<i64> {
+    Ok(connection.query_row("PRAGMA user_version", [], |row| row.get(0))?)
+}
+
+/// Upgrades the database to `VERSION`, one migration at a time.
+///
+/// Each migration runs in its own transaction along with the version bump,
+/// so an interrupted upgrade resumes where it stopped. Databases written by
+/// a newer version of moulinette are refused.
+pub fn migrate(connection: &Connection) -> Result<()> {
+    let current = version(connection)?;
+    if !(0..=VERSION).contains(&current) {
+        return Err(Error::new(ErrorKind::UnsupportedVersion(current)));
+    }
+    let tables: i64 = connection.query_row(
+        "SELECT count(*) FROM sqlite_master WHERE type = 'table'",
+        [],
+        |row| row.get(0),
+    )?;
+    if tables > 0 && current < VERSION {
+        println!(
+            "Migrating database from version {} to version {}",
+            current, VERSION
+        );
+    }
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(current as usize) {
+        let next = index as i64 + 1;
+        connection.execute_batch("BEGIN")?;
+        let result = migration(connection).and_then(|()| {
+            connection.execute_batch(&format!("PRAGMA user_version = {}", next))?;
+            Ok(())
+        });
+        match result {
+            Ok(()) => connection.execute_batch("COMMIT")?,
+            Err(e) => {
+                connection.execute_batch("ROLLBACK")?;
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::Schema;
+    use crate::sqlite::SqliteSink;
+
+    /// Some of the tables written before versioning, with a row each
+    const BASELINE: &str = r#"
+    CREATE TABLE "server" (
+      "Timestamp" TEXT,
+      "IPv6 Adress" TEXT,
+      "receiver port" INTEGER,
+      "payload" TEXT
+    );
+    CREATE TABLE "stats" (
+      "Timestamp" TEXT,
+      "Node" TEXT,
+      "success" INTEGER,
+      "layer" TEXT,
+      "rx packets" INTEGER,
+      "rx bytes" INTEGER,
+      "tx packets" INTEGER,
+      "tx multicast packets" INTEGER,
+      "tx bytes" INTEGER,
+      "tx succeeded" INTEGER,
+      "tx errors" INTEGER
+    );
+    INSERT INTO "server" VALUES ('2021-03-01 10:00:00', '2001:db8::1', 3000, 'abc');
+    INSERT INTO "stats" VALUES ('2021-03-01 10:00:00', 'm3-1', 1, 'ipv6', 1, 2, 3, 4, 5, 6, 7);
+    "#;
+
+    fn temp_database(name: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("moulinette-{}-{}.sqlite", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    /// The tables of the database and their columns
+    fn layout(connection: &Connection) -> Vec<(String, String)> {
+        connection
+            .prepare(
+                "SELECT m.name, p.name FROM sqlite_master m, pragma_table_info(m.name) p
+                 WHERE m.type = 'table' ORDER BY m.name, p.cid",
+            )
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    fn count(connection: &Connection, table: &str) -> i64 {
+        connection
+            .query_row(&format!("SELECT count(*) FROM \"{}\"", table), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn baseline_databases_are_migrated() {
+        let connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch(BASELINE).unwrap();
+        assert_eq!(version(&connection).unwrap(), 0);
+        migrate(&connection).unwrap();
+        assert_eq!(version(&connection).unwrap(), VERSION);
+        assert_eq!(count(&connection, "server"), 1);
+        assert_eq!(count(&connection, "stats"), 1);
+        assert_eq!(count(&connection, "rejects"), 0);
+        let columns = layout(&connection);
+        for table in &["server", "stats", "rejects"] {
+            let column = (table.to_string(), "experiment_id".to_string());
+            assert!(columns.contains(&column), "{:?}", column);
+        }
+    }
+
+    #[test]
+    fn migrated_databases_are_left_alone() {
+        let path = temp_database("migrations");
+        let first = {
+            let sink = SqliteSink::open(&path, &Schema::default()).unwrap();
+            sink.connection()
+                .execute_batch(r#"INSERT INTO "server" ("payload") VALUES ('abc')"#)
+                .unwrap();
+            layout(sink.connection())
+        };
+        let sink = SqliteSink::open(&path, &Schema::default()).unwrap();
+        assert_eq!(version(sink.connection()).unwrap(), VERSION);
+        assert_eq!(layout(sink.connection()), first);
+        assert_eq!(count(sink.connection(), "server"), 1);
+        migrate(sink.connection()).unwrap();
+        assert_eq!(layout(sink.connection()), first);
+        drop(sink);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn newer_databases_are_refused() {
+        let connection = Connection::open_in_memory().unwrap();
+        connection
+            .execute_batch(&format!("PRAGMA user_version = {}", VERSION + 1))
+            .unwrap();
+        let error = migrate(&connection).unwrap_err();
+        assert_eq!(error.kind.name(), "unsupported_version");
+    }
+}
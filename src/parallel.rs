@@ -0,0 +1,576 @@
+use crate::error::{Error, ErrorKind, Result};
+use crate::ingest::{ingest_experiment, ingest_serial, ingest_server, Status};
+use crate::record::{Experiment, Reject, SerialRecord, ServerRecord};
+use crate::schema::Schema;
+use crate::sink::RecordSink;
+use crate::sqlite::SqliteSink;
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// The outcome of the ingestion of each experiment, in the order they were given
+pub type Outcomes = Vec<(Experiment, Result<Status>)>;
+
+/// Number of records a worker sends to the writer thread at once
+const CHUNK_SIZE: usize = 1024;
+
+/// Records sent to the writer thread, part of a batch
+#[derive(Debug, Default)]
+struct Chunk {
+    serial: Vec<SerialRecord>,
+    server: Vec<ServerRecord>,
+    rejects: Vec<Reject>,
+}
+
+impl Chunk {
+    fn len(&self) -> usize {
+        self.serial.len() + self.server.len() + self.rejects.len()
+    }
+}
+
+/// A request of a worker to the writer thread, along with the channel the
+/// writer answers on
+enum Message {
+    Begin {
+        key: usize,
+        experiment: Experiment,
+        jobs: usize,
+        reply: Sender<Result<()>>,
+    },
+    Write {
+        key: usize,
+        chunk: Chunk,
+    },
+    Commit {
+        key: usize,
+        reply: Sender<Result<()>>,
+    },
+    End {
+        key: usize,
+        failed: bool,
+        reply: Sender<Result<()>>,
+    },
+}
+
+/// Sends the records of a worker to the writer thread, in chunks of
+/// `CHUNK_SIZE` records.
+///
+/// The channel to the writer is bounded, so that a worker parsing faster
+/// than the database is written waits instead of queuing its records.
+/// Several workers may share an experiment (e.g. one parses the serial file
+/// while another parses the server file): the writer registers it on the
+/// first `begin_experiment` and ends it once each of its `jobs` ended it, or
+/// drops it if any of them aborted it.
+struct ChannelSink {
+    key: usize,
+    jobs: usize,
+    sender: SyncSender<Message>,
+    chunk: Chunk,
+}
+
+impl ChannelSink {
+    fn send(&self, message: Message) -> Result<()> {
+        self.sender
+            .send(message)
+            .map_err(|_| Error::new(ErrorKind::Aborted))
+    }
+
+    fn request(&self, message: impl FnOnce(Sender<Result<()>>) -> Message) -> Result<()> {
+        let (reply, answer) = channel();
+        self.send(message(reply))?;
+        answer.recv().map_err(|_| Error::new(ErrorKind::Aborted))?
+    }
+
+    /// Sends the records written since the last chunk
+    fn flush(&mut self) -> Result<()> {
+        if self.chunk.len() == 0 {
+            return Ok(());
+        }
+        let chunk = std::mem::take(&mut self.chunk);
+        self.send(Message::Write {
+            key: self.key,
+            chunk,
+        })
+    }
+
+    fn flush_full(&mut self) -> Result<()> {
+        match self.chunk.len() {
+            n if n >= CHUNK_SIZE => self.flush(),
+            _ => Ok(()),
+        }
+    }
+
+    fn end(&mut self, failed: bool) -> Result<()> {
+        let key = self.key;
+        self.request(|reply| Message::End { key, failed, reply })
+    }
+}
+
+impl RecordSink for ChannelSink {
+    fn begin_experiment(&mut self, experiment: &Experiment) -> Result<()> {
+        let (key, jobs) = (self.key, self.jobs);
+        self.request(|reply| Message::Begin {
+            key,
+            experiment: experiment.clone(),
+            jobs,
+            reply,
+        })
+    }
+
+    fn end_experiment(&mut self) -> Result<()> {
+        self.end(false)
+    }
+
+    fn abort_experiment(&mut self) -> Result<()> {
+        self.chunk = Chunk::default();
+        self.end(true)
+    }
+
+    fn begin(&mut self) -> Result<()> {
+        /* The chunks already sent are dropped along with the experiment if the batch fails */
+        self.chunk = Chunk::default();
+        Ok(())
+    }
+
+    fn write_serial(&mut self, record: &SerialRecord) -> Result<()> {
+        self.chunk.serial.push(record.clone());
+        self.flush_full()
+    }
+
+    fn write_server(&mut self, record: &ServerRecord) -> Result<()> {
+        self.chunk.server.push(record.clone());
+        self.flush_full()
+    }
+
+    fn write_reject(&mut self, reject: &Reject) -> Result<()> {
+        self.chunk.rejects.push(reject.clone());
+        self.flush_full()
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        self.flush()?;
+        let key = self.key;
+        self.request(|reply| Message::Commit { key, reply })
+    }
+
+    fn rollback(&mut self) -> Result<()> {
+        /* The chunks already sent are dropped along with the experiment */
+        self.chunk = Chunk::default();
+        Ok(())
+    }
+}
+
+/// An experiment known to the writer thread
+struct Registration {
+    /* The experiment and its row id, while it is not the current one of the sink */
+    state: Option<(i64, Experiment)>,
+    /* Number of workers which have not ended the experiment yet */
+    jobs: usize,
+    /* Whether a worker failed: the experiment is dropped once all of them ended it */
+    failed: bool,
+    /* Error while writing a chunk, reported on the next commit */
+    error: Option<Error>,
+}
+
+/// The database owned by the writer thread.
+///
+/// The chunks of all the workers are written into a shared transaction,
+/// committed whenever a worker commits its batch. A worker whose chunk
+/// cannot be written is told on its next commit, and drops its experiment.
+struct Writer {
+    sink: SqliteSink,
+    experiments: HashMap<usize, Registration>,
+    /* Experiments with records in the open transaction */
+    written: HashSet<usize>,
+}
+
+impl Writer {
+    fn write(&mut self, key: usize, chunk: Chunk) {
+        let registration = match self.experiments.get_mut(&key) {
+            /* The chunk would be dropped along with the experiment anyway */
+            Some(r) if r.failed || r.error.is_some() => return,
+            Some(r) => r,
+            None => return,
+        };
+        let sink = &mut self.sink;
+        sink.swap_experiment(registration.state.take());
+        let result = (|| {
+            if sink.connection().is_autocommit() {
+                sink.begin()?;
+            }
+            for record in &chunk.serial {
+                sink.write_serial(record)?;
+            }
+            for record in &chunk.server {
+                sink.write_server(record)?;
+            }
+            for reject in &chunk.rejects {
+                sink.write_reject(reject)?;
+            }
+            Ok(())
+        })();
+        registration.state = sink.swap_experiment(None);
+        registration.error = result.err();
+        self.written.insert(key);
+    }
+
+    /// Commits the open transaction, on behalf of `key`
+    fn commit(&mut self, key: Option<usize>) -> Result<()> {
+        if let Some(e) = key
+            .and_then(|key| self.experiments.get_mut(&key))
+            .and_then(|r| r.error.take())
+        {
+            return Err(e);
+        }
+        if self.sink.connection().is_autocommit() {
+            return Ok(());
+        }
+        let written = std::mem::take(&mut self.written);
+        let result = self.sink.commit();
+        if result.is_err() {
+            /* The other experiments lost their records as well */
+            self.sink.rollback()?;
+            for other in written.iter().filter(|k| Some(**k) != key) {
+                if let Some(registration) = self.experiments.get_mut(other) {
+                    registration.error = Some(Error::new(ErrorKind::Aborted));
+                }
+            }
+        }
+        result
+    }
+
+    fn begin_experiment(&mut self, key: usize, experiment: &Experiment, jobs: usize) -> Result<()> {
+        match self.experiments.get(&key) {
+            Some(registration) if registration.failed => Err(Error::new(ErrorKind::Aborted)),
+            Some(_) => Ok(()),
+            None => {
+                let result = self.sink.begin_experiment(experiment);
+                let registration = Registration {
+                    state: self.sink.swap_experiment(None),
+                    jobs,
+                    failed: result.is_err(),
+                    error: None,
+                };
+                self.experiments.insert(key, registration);
+                if !self.sink.connection().is_autocommit() {
+                    /* Its row is part of the open transaction */
+                    self.written.insert(key);
+                }
+                result
+            }
+        }
+    }
+
+    fn end_experiment(&mut self, key: usize, failed: bool) -> Result<()> {
+        let registration = match self.experiments.get_mut(&key) {
+            Some(registration) => registration,
+            None => return Ok(()),
+        };
+        registration.jobs -= 1;
+        registration.failed |= failed;
+        if registration.jobs > 0 || registration.state.is_none() {
+            return Ok(());
+        }
+        if registration.failed {
+            /* The records of the other experiments must not be dropped with this one */
+            self.commit(None)?;
+        }
+        let registration = self.experiments.get_mut(&key).expect("registered above");
+        self.sink.swap_experiment(registration.state.take());
+        if registration.failed {
+            self.sink.abort_experiment()
+        } else {
+            self.sink.end_experiment()
+        }
+    }
+}
+
+/// Owns the database, and writes the chunks of all the workers one at a time
+fn write(sink: SqliteSink, messages: Receiver<Message>) -> Result<()> {
+    let mut writer = Writer {
+        sink,
+        experiments: HashMap::new(),
+        written: HashSet::new(),
+    };
+    for message in messages {
+        match message {
+            Message::Begin {
+                key,
+                experiment,
+                jobs,
+                reply,
+            } => {
+                let _ = reply.send(writer.begin_experiment(key, &experiment, jobs));
+            }
+            Message::Write { key, chunk } => writer.write(key, chunk),
+            Message::Commit { key, reply } => {
+                let failed = writer.experiments.get(&key).is_some_and(|r| r.failed);
+                let _ = reply.send(match failed {
+                    true => Err(Error::new(ErrorKind::Aborted)),
+                    false => writer.commit(Some(key)),
+                });
+            }
+            Message::End { key, failed, reply } => {
+                let _ = reply.send(writer.end_experiment(key, failed));
+            }
+        }
+    }
+    writer.commit(None)?;
+    println!("Vacuuming");
+    writer.sink.vacuum()
+}
+
+/// Combines the outcomes of the jobs of an experiment: it is partial if one
+/// of them is, and fails with the first error which is not a consequence of
+/// an error of another worker
+fn record_outcome(outcome: &mut Result<Status>, result: Result<Status>) {
+    match (&*outcome, result) {
+        (Ok(current), Ok(status)) => *outcome = Ok(current.and(status)),
+        (Ok(_), Err(e)) => *outcome = Err(e),
+        (Err(current), Err(e)) if matches!(current.kind, ErrorKind::Aborted) => *outcome = Err(e),
+        (Err(_), _) => { /* Keep the first error */ }
+    }
+}
+
+/// Runs `job` on each of the `jobs` with `workers` threads
+fn run_jobs<J: Send>(jobs: Vec<J>, workers: usize, job: impl Fn(J) + Sync) {
+    let queue = Mutex::new(jobs.into_iter());
+    thread::scope(|scope| {
+        for _ in 0..workers.max(1) {
+            scope.spawn(|| loop {
+                /* Release the lock before running the job */
+                let next = queue.lock().unwrap().next();
+                match next {
+                    Some(j) => job(j),
+                    None => break,
+                }
+            });
+        }
+    });
+}
+
+/// Ingests the `experiments` into the database at `path`, using `workers`
+/// threads.
+///
+/// The serial and server files of each experiment are parsed by separate
+/// workers, which send their batches to a single writer thread owning the
+/// database.
+pub fn ingest_shared(
+    path: &str,
+    schema: &Arc<Schema>,
+    experiments: Vec<Experiment>,
+    workers: usize,
+    recover: bool,
+) -> Result<Outcomes> {
+    let mut sink = SqliteSink::open(path, schema)?;
+    if workers <= 1 {
+        let outcomes = experiments
+            .into_iter()
+            .map(|experiment| {
+                println!("Experiment: {}", experiment.name);
+                let result = ingest_experiment(&mut sink, schema, &experiment, recover);
+                (experiment, result)
+            })
+            .collect();
+        println!("Vacuuming");
+        sink.vacuum()?;
+        return Ok(outcomes);
+    }
+
+    let outcomes: Vec<Mutex<Result<Status>>> = experiments
+        .iter()
+        .map(|_| Mutex::new(Ok(Status::Complete)))
+        .collect();
+    /* At most one chunk per worker waits for the writer */
+    let (sender, messages) = sync_channel(workers);
+    let writer = thread::spawn(move || write(sink, messages));
+    let jobs: Vec<(usize, bool, SyncSender<Message>)> = (0..experiments.len())
+        .flat_map(|key| vec![(key, true, sender.clone()), (key, false, sender.clone())])
+        .collect();
+    /* The writer stops once every worker dropped its sender */
+    drop(sender);
+    run_jobs(jobs, workers, |(key, serial, sender)| {
+        let experiment = &experiments[key];
+        let mut sink = ChannelSink {
+            key,
+            jobs: 2,
+            sender,
+            chunk: Chunk::default(),
+        };
+        let result = sink.begin_experiment(experiment).and_then(|()| {
+            if serial {
+                println!("Parsing serial data of {}", experiment.name);
+                ingest_serial(&mut sink, schema, &experiment.serial_file, recover)
+            } else {
+                println!("Parsing server data of {}", experiment.name);
+                ingest_server(&mut sink, &experiment.server_file, recover)
+            }
+        });
+        /* Always end the experiment, so that the writer knows when both files are done */
+        let end = match result {
+            Ok(_) => sink.end_experiment(),
+            Err(_) => sink.abort_experiment(),
+        }
+        .map(|()| Status::Complete);
+        let mut outcome = outcomes[key].lock().unwrap();
+        record_outcome(&mut outcome, result);
+        record_outcome(&mut outcome, end);
+    });
+    writer.join().expect("the writer thread panicked")?;
+
+    Ok(experiments
+        .into_iter()
+        .zip(outcomes)
+        .map(|(experiment, outcome)| (experiment, outcome.into_inner().unwrap()))
+        .collect())
+}
+
+/// Ingests each of the `experiments` into its own database, at the path
+/// given by `path`, using `workers` threads
+pub fn ingest_split(
+    schema: &Arc<Schema>,
+    experiments: Vec<Experiment>,
+    workers: usize,
+    recover: bool,
+    path: impl Fn(&Experiment) -> String + Sync,
+) -> Outcomes {
+    let outcomes: Vec<Mutex<Result<Status>>> = experiments
+        .iter()
+        .map(|_| Mutex::new(Ok(Status::Complete)))
+        .collect();
+    run_jobs((0..experiments.len()).collect(), workers, |key| {
+        let experiment = &experiments[key];
+        println!("Experiment: {}", experiment.name);
+        let result = SqliteSink::open(&path(experiment), schema).and_then(|mut sink| {
+            let status = ingest_experiment(&mut sink, schema, experiment, recover)?;
+            sink.vacuum()?;
+            Ok(status)
+        });
+        *outcomes[key].lock().unwrap() = result;
+    });
+    experiments
+        .into_iter()
+        .zip(outcomes)
+        .map(|(experiment, outcome)| (experiment, outcome.into_inner().unwrap()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::types::Value;
+    use rusqlite::Connection;
+    use std::path::{Path, PathBuf};
+
+    /// Writes the zstd compressed serial and server files of an experiment
+    fn write_experiment(directory: &Path, name: &str) -> Experiment {
+        let mut serial = String::new();
+        let mut server = String::from("Timestamp,IPv6 Adress,receiver port,payload\n");
+        for node in 1..=3 {
+            serial += &format!("1.0;m3-{};energy,cpu,radio\n", node);
+            serial += &format!("1.0;m3-{};neighbor_stats,L2 address,fresh,etx,sent,received,rssi (dBm),lqi,avg tx time (µs)\n", node);
+        }
+        for i in 0..3000 {
+            let node = i % 3 + 1;
+            let time = 2.0 + i as f64 / 10.0;
+            serial += &format!("{};m3-{};energy,{},{}\n", time, node, i, i * 2);
+            serial += &format!(
+                "{};m3-{};neighbor_stats,{:x},1,1.5,{},{},-70,255,12\n",
+                time, node, i, i, i
+            );
+            if i % 500 == 0 {
+                serial += &format!("{};m3-{}\n", time, node);
+                server += "not a server line\n";
+            }
+            server += &format!(
+                "2021-03-01 10:00:{:02}.{:03}Z,2001:db8::{},3000,{}-{}\n",
+                i / 1000,
+                i % 1000,
+                node,
+                name,
+                i
+            );
+        }
+        let compress = |file: &str, content: &str| {
+            let path = directory.join(format!("{}-{}.zst", name, file));
+            let compressed = zstd::encode_all(content.as_bytes(), 0).unwrap();
+            std::fs::write(&path, compressed).unwrap();
+            path.to_string_lossy().to_string()
+        };
+        Experiment::new(
+            name,
+            &compress("serial", &serial),
+            &compress("server", &server),
+        )
+    }
+
+    /// The rows of every table, with the experiment name instead of its id
+    fn dump(path: &str) -> Vec<(String, Vec<String>)> {
+        let connection = Connection::open(path).unwrap();
+        let tables: Vec<String> = connection
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table' AND name != 'experiments' ORDER BY name")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        let mut rows = Vec::new();
+        for table in tables {
+            let query = format!(
+                r#"SELECT e."Name", t.* FROM "{}" t LEFT JOIN experiments e ON e.id = t.experiment_id"#,
+                table
+            );
+            let mut statement = connection.prepare(&query).unwrap();
+            let count = statement.column_count();
+            let id = statement.column_index("experiment_id").unwrap();
+            let mut table_rows: Vec<(String, Vec<String>)> = statement
+                .query_map([], |row| {
+                    let values = (0..count)
+                        .filter(|i| *i != id)
+                        .map(|i| row.get::<_, Value>(i).map(|v| format!("{:?}", v)))
+                        .collect::<rusqlite::Result<_>>()?;
+                    Ok((table.clone(), values))
+                })
+                .unwrap()
+                .collect::<rusqlite::Result<_>>()
+                .unwrap();
+            table_rows.sort();
+            rows.append(&mut table_rows);
+        }
+        rows
+    }
+
+    #[test]
+    fn workers_write_the_same_rows() {
+        let directory: PathBuf =
+            std::env::temp_dir().join(format!("moulinette-parallel-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        let experiments: Vec<Experiment> = ["a", "b", "c"]
+            .iter()
+            .map(|name| write_experiment(&directory, name))
+            .collect();
+        let schema = Arc::new(Schema::default());
+
+        let mut dumps = Vec::new();
+        for workers in &[1, 4] {
+            let path = directory.join(format!("{}.sqlite", workers));
+            let path = path.to_string_lossy();
+            let outcomes =
+                ingest_shared(&path, &schema, experiments.clone(), *workers, false).unwrap();
+            for (experiment, outcome) in outcomes {
+                assert!(outcome.is_ok(), "{}: {:?}", experiment.name, outcome);
+            }
+            dumps.push(dump(&path));
+        }
+        assert_eq!(dumps[0].len(), dumps[1].len());
+        assert!(dumps[0] == dumps[1], "the parallel ingestion differs");
+        let count = |table: &str| dumps[0].iter().filter(|(t, _)| t == table).count();
+        assert_eq!(count("energy"), 9000);
+        assert_eq!(count("neighbor_stats"), 9000);
+        assert_eq!(count("server"), 9000);
+        assert_eq!(count("rejects"), 36);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,860 @@
+use crate::error::{Error, ErrorKind, Result};
+use crate::record::*;
+use crate::schema::{Schema, TagSchema, RESERVED_COLUMNS};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+fn field<'a>(data: &[&'a str], index: usize, column: &str) -> Result<&'a str> {
+    data.get(index)
+        .copied()
+        .ok_or_else(|| Error::new(ErrorKind::MissingField).with_column(column))
+}
+
+fn text(data: &[&str], index: usize, column: &str) -> Result<String> {
+    Ok(field(data, index, column)?.to_string())
+}
+
+fn integer(data: &[&str], index: usize, column: &str) -> Result<i64> {
+    let raw = field(data, index, column)?;
+    raw.replace(' ', "")
+        .parse::<i64>()
+        .map_err(|e| Error::from(e).with_column(column).with_value(raw))
+}
+
+/// The fields of a formatted serial line, looked up by their header name
+struct Fields<'a> {
+    header: &'a [String],
+    data: &'a [&'a str],
+}
+
+impl<'a> Fields<'a> {
+    fn position(&self, column: &str) -> Result<usize> {
+        self.header
+            .iter()
+            .position(|name| name.trim().eq_ignore_ascii_case(column))
+            /* The first field of the data is the tag */
+            .map(|position| position + 1)
+            .ok_or_else(|| Error::new(ErrorKind::MissingField).with_column(column))
+    }
+
+    fn text(&self, column: &str) -> Result<String> {
+        text(self.data, self.position(column)?, column)
+    }
+
+    fn integer(&self, column: &str) -> Result<i64> {
+        integer(self.data, self.position(column)?, column)
+    }
+}
+
+/// The header a tag is expected to have when none was captured
+pub fn default_header(tag: &str) -> Option<Vec<String>> {
+    SerialRecord::columns_for(tag)
+        .map(|columns| columns[2..].iter().map(|c| c.to_string()).collect())
+}
+
+/// Whether `header` names at least one of the columns of the `expected`
+/// header, or, for tags without an expected header, whether it looks like a
+/// header at all (no empty or numeric names)
+fn is_plausible_header(expected: Option<&[String]>, header: &[String]) -> bool {
+    match expected {
+        Some(columns) => header
+            .iter()
+            .any(|name| columns.iter().any(|c| name.trim().eq_ignore_ascii_case(c))),
+        None => {
+            !header.is_empty()
+                && header.iter().all(|name| {
+                    !name.trim().is_empty() && matches!(infer_value(name), Value::Text(_))
+                })
+        }
+    }
+}
+
+/// Converts a raw field to an integer or a real when possible, and keeps it as text otherwise
+pub fn infer_value(raw: &str) -> Value {
+    let stripped = raw.replace(' ', "");
+    if let Ok(v) = stripped.parse::<i64>() {
+        return Value::Integer(v);
+    }
+    /* Rust also parses "inf" and "NaN", which we want to keep as text */
+    if stripped.bytes().any(|b| b.is_ascii_digit()) {
+        if let Ok(v) = stripped.parse::<f64>() {
+            return Value::Real(v);
+        }
+    }
+    Value::Text(raw.to_string())
+}
+
+/// The names of the `count` columns of a generic record: fields beyond the
+/// header, or with an empty name, get a positional name, and names which are
+/// reserved or already taken (SQLite compares them case-insensitively) get a
+/// number, as in `a 2`
+fn column_names(header: &[String], count: usize) -> Vec<String> {
+    let mut taken: HashSet<String> = RESERVED_COLUMNS
+        .iter()
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    (0..count)
+        .map(|i| {
+            let name = match header.get(i).map(|name| name.trim()) {
+                Some(name) if !name.is_empty() => name.to_string(),
+                _ => format!("field {}", i + 1),
+            };
+            let name = (1..)
+                .map(|n| match n {
+                    1 => name.clone(),
+                    n => format!("{} {}", name, n),
+                })
+                .find(|candidate| !taken.contains(&candidate.to_ascii_lowercase()))
+                .expect("the numbered names are endless");
+            taken.insert(name.to_ascii_lowercase());
+            name
+        })
+        .collect()
+}
+
+/// Builds the record of a tag without a dedicated variant: fields beyond the
+/// header get a positional name, missing ones are null
+fn parse_generic(
+    timestamp: DateTime<Utc>,
+    node: String,
+    tag: &str,
+    fields: &Fields,
+) -> Result<SerialRecord> {
+    if fields.header.is_empty() {
+        return Err(Error::new(ErrorKind::UnknownTag));
+    }
+    let count = fields.header.len().max(fields.data.len() - 1);
+    let header = column_names(fields.header, count);
+    let values = (0..count)
+        .map(|i| {
+            fields
+                .data
+                .get(i + 1)
+                .map_or(Value::Null, |raw| infer_value(raw))
+        })
+        .collect();
+    Ok(SerialRecord::Generic(Generic {
+        timestamp,
+        node,
+        tag: tag.to_string(),
+        table: tag.to_string(),
+        header,
+        values,
+    }))
+}
+
+/// Builds the record of a tag declared in the schema with a layout which
+/// differs from the built-in one
+fn parse_declared(
+    timestamp: DateTime<Utc>,
+    node: &str,
+    entry: &TagSchema,
+    fields: &Fields,
+) -> Result<SerialRecord> {
+    let values = entry
+        .columns
+        .iter()
+        .map(|column| {
+            let raw = field(
+                fields.data,
+                fields.position(column.header())?,
+                column.header(),
+            )?;
+            column
+                .parse_rule()
+                .parse(raw)
+                .map_err(|e| e.with_column(&column.name))
+        })
+        .collect::<Result<Vec<Value>>>()
+        .map_err(|e| e.with_node(node).with_tag(&entry.name))?;
+    Ok(SerialRecord::Generic(Generic {
+        timestamp,
+        node: node.to_string(),
+        tag: entry.name.clone(),
+        table: entry.table().to_string(),
+        header: entry.columns.iter().map(|c| c.name.clone()).collect(),
+        values,
+    }))
+}
+
+/// Converts the epoch (in seconds) printed by the serial aggregator.
+///
+/// The decimal is parsed exactly, down to the nanosecond: going through a
+/// `f64` loses precision below the microsecond.
+pub fn parse_timestamp(epoch: &str) -> Result<DateTime<Utc>> {
+    let invalid =
+        |e: std::num::ParseIntError| Error::from(e).with_column("Timestamp").with_value(epoch);
+    let out_of_range = || {
+        Error::new(ErrorKind::InvalidTimestamp(None))
+            .with_column("Timestamp")
+            .with_value(epoch)
+    };
+    let (seconds, fraction) = epoch.trim().split_once('.').unwrap_or((epoch.trim(), ""));
+    let mut seconds: i64 = seconds.parse().map_err(invalid)?;
+    let mut nanoseconds: u32 = if fraction.is_empty() {
+        0
+    } else {
+        /* Only digits: the integer parsing would accept a sign, and overflow on long fractions */
+        if let Some(c) = fraction.chars().find(|c| !c.is_ascii_digit()) {
+            /* A lone non-digit never parses, which gives the usual invalid digit error */
+            return Err(invalid(c.to_string().parse::<u8>().unwrap_err()));
+        }
+        format!("{:0<9}", &fraction[..fraction.len().min(9)])
+            .parse()
+            .map_err(invalid)?
+    };
+    if epoch.trim_start().starts_with('-') && nanoseconds > 0 {
+        seconds -= 1;
+        nanoseconds = 1_000_000_000 - nanoseconds;
+    }
+    let timestamp = NaiveDateTime::from_timestamp_opt(seconds, nanoseconds)
+        .map(|timestamp| DateTime::from_utc(timestamp, Utc))
+        .filter(in_nanosecond_range)
+        .ok_or_else(out_of_range)?;
+    Ok(timestamp)
+}
+
+/// Whether the timestamp can be stored as nanoseconds since the epoch in an `i64`
+fn in_nanosecond_range(timestamp: &DateTime<Utc>) -> bool {
+    timestamp
+        .timestamp()
+        .checked_mul(1_000_000_000)
+        .and_then(|ns| ns.checked_add(i64::from(timestamp.timestamp_subsec_nanos())))
+        .is_some()
+}
+
+/// Parses the comma separated data of a serial line, whose first field is the tag.
+///
+/// The fields are expected in the order of the default header of the tag;
+/// tags without a default header are rejected.
+pub fn parse_serial_record(
+    timestamp: DateTime<Utc>,
+    node: &str,
+    data: &[&str],
+) -> Result<SerialRecord> {
+    let tag = field(data, 0, "tag")?;
+    let header = default_header(tag).unwrap_or_default();
+    parse_with_header(timestamp, node, &header, data)
+}
+
+/// Parses the comma separated data of a serial line, resolving the fields
+/// through `header`, the names of the fields following the tag.
+///
+/// Tags without a dedicated record produce a `SerialRecord::Generic`.
+pub fn parse_with_header(
+    timestamp: DateTime<Utc>,
+    node: &str,
+    header: &[String],
+    data: &[&str],
+) -> Result<SerialRecord> {
+    let tag = field(data, 0, "tag")?;
+    let fields = Fields { header, data };
+    parse_tagged(timestamp, node, tag, &fields).map_err(|e| e.with_node(node).with_tag(tag))
+}
+
+fn parse_tagged(
+    timestamp: DateTime<Utc>,
+    node: &str,
+    tag: &str,
+    fields: &Fields,
+) -> Result<SerialRecord> {
+    let node = node.to_string();
+    let record = match tag {
+        "neighbor_stats" => SerialRecord::NeighborStats(NeighborStats {
+            timestamp,
+            node,
+            l2_address: fields.text("L2 address")?,
+            fresh: fields.text("fresh")?,
+            etx: fields.text("etx")?,
+            sent: fields.integer("sent")?,
+            received: fields.integer("received")?,
+            rssi: fields.integer("rssi (dBm)")?,
+            lqi: fields.integer("lqi")?,
+            avg_tx_time: fields.integer("avg tx time (µs)")?,
+        }),
+        "rpl_stats" => SerialRecord::RplStats(RplStats {
+            timestamp,
+            node,
+            packet_type: fields.text("Packet Type")?,
+            measurement_type: fields.text("Measurement Type")?,
+            rx_unicast: fields.integer("RX unicast")?,
+            tx_unicast: fields.integer("TX unicast")?,
+            rx_multicast: fields.integer("RX multicast")?,
+            tx_multicast: fields.integer("TX multicast")?,
+        }),
+        "rpl_stats_dodag" => SerialRecord::RplStatsDodag(RplStatsDodag {
+            timestamp,
+            node,
+            instance_id: fields.text("Instance ID")?,
+            ipv6_address: fields.text("IPv6 Adress")?,
+            rank: fields.integer("Rank")?,
+            role: fields.text("Role")?,
+            prefix_information: fields.text("Prefix Information")?,
+            trickle_interval_size_min: fields.integer("Trickle Interval Size Min")?,
+            trickle_interval_size_max: fields.integer("Trickle Interval Size Max")?,
+            trickle_redundancy_constant: fields.integer("Trickle Redundancy Constant")?,
+            trickle_counter: fields.integer("Trickle Counter")?,
+            trickle_tc: fields.integer("Trickle TC")?,
+        }),
+        "rpl_stats_instance" => SerialRecord::RplStatsInstance(RplStatsInstance {
+            timestamp,
+            node,
+            instance_id: fields.text("Instance ID")?,
+            interface_id: fields.text("Interface ID")?,
+            mode_of_operation: fields.text("Mode of Operation")?,
+            objective_code_point: fields.text("Objective Code Point")?,
+            min_hop_rank_increase: fields.text("Min Hop Rank Increase")?,
+            max_rank_increase: fields.text("Max Rank Increase")?,
+        }),
+        "rpl_stats_parent" => SerialRecord::RplStatsParent(RplStatsParent {
+            timestamp,
+            node,
+            instance_id: fields.text("Instance ID")?,
+            ipv6_address: fields.text("IPv6 Adress")?,
+            rank: fields.text("Rank")?,
+        }),
+        "rpl_status" => SerialRecord::RplStatus(RplStatus {
+            timestamp,
+            node,
+            table_type: fields.text("Type of table")?,
+            table_index: fields.text("Index of the table")?,
+            table_status: fields.text("Table status")?,
+        }),
+        "stats" => SerialRecord::Stats(Stats {
+            timestamp,
+            node,
+            success: fields.integer("success")?,
+            layer: fields.text("layer")?,
+            rx_packets: fields.integer("rx packets")?,
+            rx_bytes: fields.integer("rx bytes")?,
+            tx_packets: fields.integer("tx packets")?,
+            tx_multicast_packets: fields.integer("tx multicast packets")?,
+            tx_bytes: fields.integer("tx bytes")?,
+            tx_succeeded: fields.integer("tx succeeded")?,
+            tx_errors: fields.integer("tx errors")?,
+        }),
+        "udp" => SerialRecord::Udp(Udp {
+            timestamp,
+            node,
+            payload_size: fields.text("payload size")?,
+            destination_address: fields.text("destination address")?,
+            destination_port: fields.text("destination port")?,
+            payload: fields.text("payload")?,
+        }),
+        _ => return parse_generic(timestamp, node, tag, fields),
+    };
+    Ok(record)
+}
+
+/// Messages printed by the firmware when it boots
+const BOOT_BANNERS: &[&str] = &["This is RIOT!"];
+
+/// Stateful parser for the serial aggregator output.
+///
+/// The first line a node prints for a given tag is its header: it is
+/// remembered and no record is produced for it. The following lines are
+/// mapped to columns through the names of this header, so that firmware
+/// revisions reordering or adding columns are handled.
+///
+/// A node which prints a header again, or its boot banner after some data,
+/// has rebooted: a `SerialRecord::Reboot` is produced for it.
+///
+/// Tags stored as in the built-in schema are parsed into their dedicated
+/// `SerialRecord` variant; other tags of the schema, and tags it does not
+/// know about, are parsed into `SerialRecord::Generic`.
+pub struct SerialParser {
+    schema: Arc<Schema>,
+
+    /* Tags of the schema which are parsed into their dedicated record */
+    builtin: HashSet<String>,
+
+    /* Header printed by each node, for each tag */
+    node_headers: HashMap<(String, String), Vec<String>>,
+
+    /* First header seen for each tag */
+    headers: HashMap<String, Vec<String>>,
+
+    /* Tags with a column read at its default position */
+    renamed: HashSet<String>,
+
+    /* Header mismatches since the last call to take_warnings */
+    warnings: Vec<String>,
+
+    /* Number of reboots of each node, and whether it printed data since the last one */
+    boots: HashMap<String, (i64, bool)>,
+
+    /* Boot in which each node last printed its header of each tag */
+    header_boots: HashMap<(String, String), i64>,
+
+    /* Reboots detected since the last call to take_reboots */
+    reboots: Vec<Reboot>,
+}
+
+impl Default for SerialParser {
+    fn default() -> Self {
+        Self::with_schema(Arc::new(Schema::default()))
+    }
+}
+
+impl SerialParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_schema(schema: Arc<Schema>) -> Self {
+        let builtin = schema
+            .tags
+            .iter()
+            .filter(|t| t.is_builtin())
+            .map(|t| t.name.clone())
+            .collect();
+        SerialParser {
+            schema,
+            builtin,
+            node_headers: HashMap::new(),
+            headers: HashMap::new(),
+            renamed: HashSet::new(),
+            warnings: Vec::new(),
+            boots: HashMap::new(),
+            header_boots: HashMap::new(),
+            reboots: Vec::new(),
+        }
+    }
+
+    /// The first header seen for each tag
+    pub fn headers(&self) -> &HashMap<String, Vec<String>> {
+        &self.headers
+    }
+
+    /// The headers which differ from the first seen one or cannot be used,
+    /// described since the last call, in the order of the lines
+    pub fn take_warnings(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    /// Names the columns of `expected` missing from `header` after the field
+    /// at their default position, unless that field is another known
+    /// column: a node printing a slightly different name (e.g. `us` for
+    /// `µs`) is read by position rather than losing the whole tag
+    fn resolve_header(
+        &mut self,
+        node: &str,
+        tag: &str,
+        mut header: Vec<String>,
+        expected: &[String],
+    ) -> Vec<String> {
+        let known = |name: &str| expected.iter().any(|c| name.trim().eq_ignore_ascii_case(c));
+        let mut renamed = Vec::new();
+        for (position, column) in expected.iter().enumerate() {
+            if header
+                .iter()
+                .any(|name| name.trim().eq_ignore_ascii_case(column))
+            {
+                continue;
+            }
+            match header.get(position) {
+                Some(name) if !known(name) => {
+                    renamed.push((name.clone(), column.clone()));
+                    header[position] = column.clone();
+                }
+                _ => { /* The lines will lack the column */ }
+            }
+        }
+        /* Once per tag, rather than for every node */
+        if !renamed.is_empty() && self.renamed.insert(tag.to_string()) {
+            self.warnings.push(format!(
+                "node {} prints the {} columns {:?}, read by position as {:?}",
+                node,
+                tag,
+                renamed.iter().map(|(name, _)| name).collect::<Vec<_>>(),
+                renamed.iter().map(|(_, column)| column).collect::<Vec<_>>()
+            ));
+        }
+        header
+    }
+
+    /// The reboots detected since the last call, in the order of the lines
+    pub fn take_reboots(&mut self) -> Vec<Reboot> {
+        std::mem::take(&mut self.reboots)
+    }
+
+    /// Records a reboot of `node`
+    fn reboot(&mut self, timestamp: DateTime<Utc>, node: &str, cause: &str) {
+        *self.boots.entry(node.to_string()).or_default() = (self.boot(node) + 1, false);
+        self.reboots.push(Reboot {
+            timestamp,
+            node: node.to_string(),
+            cause: cause.to_string(),
+        });
+    }
+
+    /// The number of reboots of `node` detected so far
+    fn boot(&self, node: &str) -> i64 {
+        self.boots.get(node).map_or(0, |(boot, _)| *boot)
+    }
+
+    /// Records a reboot of `node` when `message` is a boot banner printed
+    /// after some data: the banner of the first boot is often printed
+    /// before the serial aggregator listens
+    fn check_banner(&mut self, timestamp: DateTime<Utc>, node: &str, message: &str) {
+        let printed_data = self.boots.get(node).is_some_and(|(_, data)| *data);
+        if printed_data && BOOT_BANNERS.iter().any(|banner| message.contains(banner)) {
+            self.reboot(timestamp, node, "banner");
+        }
+    }
+
+    /// Whether the fields of a line of `tag`, whose header is known, are a
+    /// header again: the same one, or names of the columns of the tag
+    /// without any number
+    fn is_header_line(&self, node: &str, tag: &str, fields: &[String]) -> bool {
+        if self.node_headers[&(node.to_string(), tag.to_string())] == fields {
+            return true;
+        }
+        match self.schema.tag(tag).map(|t| t.header()) {
+            Some(expected) => {
+                fields
+                    .iter()
+                    .all(|f| matches!(infer_value(f), Value::Text(_)))
+                    && is_plausible_header(Some(&expected), fields)
+            }
+            None => false,
+        }
+    }
+
+    /// Stores the header printed by `node` for `tag`.
+    ///
+    /// When the captured header does not name any known column (e.g. because
+    /// the node printed it before the serial aggregator was listening, and
+    /// the captured line is actually data), we fall back to the header
+    /// declared in the schema, or to positional names for the tags which are
+    /// not declared.
+    fn add_header(&mut self, node: &str, tag: &str, header: Vec<String>) {
+        let expected = self.schema.tag(tag).map(|t| t.header());
+        let header = if is_plausible_header(expected.as_deref(), &header) {
+            match self.headers.get(tag) {
+                None => {
+                    self.headers.insert(tag.to_string(), header.clone());
+                }
+                Some(first) if *first != header => {
+                    self.warnings.push(format!(
+                        "node {} prints the {} header {:?}, which differs from the first seen one {:?}",
+                        node, tag, header, first
+                    ));
+                }
+                Some(_) => { /* Same header as the other nodes */ }
+            }
+            self.resolve_header(node, tag, header, expected.as_deref().unwrap_or_default())
+        } else {
+            self.warnings.push(format!(
+                "unusable {} header {:?} for node {}, falling back to the default one",
+                tag, header, node
+            ));
+            expected.unwrap_or_else(|| column_names(&[], header.len()))
+        };
+        self.node_headers
+            .insert((node.to_string(), tag.to_string()), header);
+    }
+
+    /// Parses a data line of `tag`, whose header has already been seen
+    fn parse_data(
+        &self,
+        timestamp: DateTime<Utc>,
+        node: &str,
+        tag: &str,
+        data: &[&str],
+    ) -> Result<SerialRecord> {
+        let header = &self.node_headers[&(node.to_string(), tag.to_string())];
+        match self.schema.tag(tag) {
+            Some(entry) if !self.builtin.contains(tag) => {
+                parse_declared(timestamp, node, entry, &Fields { header, data })
+            }
+            _ => parse_with_header(timestamp, node, header, data),
+        }
+    }
+
+    /// Builds the record of the `info` and `output` lines, which are not
+    /// split into fields
+    fn message(
+        &self,
+        timestamp: DateTime<Utc>,
+        node: &str,
+        tag: &str,
+        message: &str,
+    ) -> SerialRecord {
+        match self.schema.tag(tag) {
+            Some(entry) if !self.builtin.contains(tag) => SerialRecord::Generic(Generic {
+                timestamp,
+                node: node.to_string(),
+                tag: tag.to_string(),
+                table: entry.table().to_string(),
+                header: entry
+                    .columns
+                    .iter()
+                    .take(1)
+                    .map(|c| c.name.clone())
+                    .collect(),
+                values: vec![Value::from(message)],
+            }),
+            _ if tag == "info" => SerialRecord::Info(Info {
+                timestamp,
+                node: node.to_string(),
+                message: message.to_string(),
+            }),
+            _ => SerialRecord::Output(Output {
+                timestamp,
+                node: node.to_string(),
+                stdout: message.to_string(),
+            }),
+        }
+    }
+
+    /// Parses a `timestamp;node;data` line, returning `None` for header lines
+    pub fn parse_line(&mut self, line: &str) -> Result<Option<SerialRecord>> {
+        let splitted: Vec<&str> = line.split(';').collect();
+        let node = field(&splitted, 1, "Node")?;
+        let timestamp =
+            parse_timestamp(field(&splitted, 0, "Timestamp")?).map_err(|e| e.with_node(node))?;
+        let data = field(&splitted, 2, "data")?;
+        let nested_splitted: Vec<&str> = data.split(',').collect();
+        if nested_splitted.len() <= 1 {
+            self.check_banner(timestamp, node, data);
+            return Ok(Some(self.message(timestamp, node, "output", data)));
+        }
+
+        /* Formatted Data */
+        let line_type = nested_splitted[0];
+        let key = (node.to_string(), line_type.to_string());
+        match (line_type, self.node_headers.contains_key(&key)) {
+            ("info", is_known) => {
+                if !is_known {
+                    /* We manually add the header because we print it too early so the serial aggregator does not have time to read it */
+                    self.node_headers.insert(key, vec!["Message".to_string()]);
+                }
+                self.check_banner(timestamp, node, data);
+                Ok(Some(self.message(timestamp, node, "info", data)))
+            }
+            (line_type, true) => {
+                let fields: Vec<String> =
+                    nested_splitted[1..].iter().map(|s| s.to_string()).collect();
+                if self.is_header_line(node, line_type, &fields) {
+                    /* A second header in the same boot means the node rebooted */
+                    if self.header_boots.get(&key) == Some(&self.boot(node)) {
+                        self.reboot(timestamp, node, "header");
+                    }
+                    self.header_boots.insert(key, self.boot(node));
+                    self.add_header(node, line_type, fields);
+                    return Ok(None);
+                }
+                /* It's data and it's not the first line */
+                let record = self.parse_data(timestamp, node, line_type, &nested_splitted)?;
+                self.boots.entry(node.to_string()).or_default().1 = true;
+                Ok(Some(record))
+            }
+            (line_type, false) => {
+                /* It's the first line: we add it as an header */
+                let header = nested_splitted[1..].iter().map(|s| s.to_string()).collect();
+                self.header_boots.insert(key, self.boot(node));
+                self.add_header(node, line_type, header);
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Parses a `timestamp,ipv6,port,payload` line of the server output
+pub fn parse_server_line(line: &str) -> Result<ServerRecord> {
+    let splitted: Vec<&str> = line.split(',').collect();
+    let raw = field(&splitted, 0, "Timestamp")?;
+    let timestamp = DateTime::parse_from_rfc3339(&raw.replace(' ', "T"))
+        .map_err(|e| Error::from(e).with_column("Timestamp").with_value(raw))?;
+    let timestamp = DateTime::from_utc(timestamp.naive_utc(), Utc);
+    if !in_nanosecond_range(&timestamp) {
+        return Err(Error::new(ErrorKind::InvalidTimestamp(None))
+            .with_column("Timestamp")
+            .with_value(raw));
+    }
+    Ok(ServerRecord {
+        timestamp,
+        ipv6_address: text(&splitted, 1, "IPv6 Adress")?,
+        port: integer(&splitted, 2, "receiver port")?,
+        payload: text(&splitted, 3, "payload")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NEIGHBOR_HEADER: &str =
+        "neighbor_stats,L2 address,fresh,etx,sent,received,rssi (dBm),lqi,avg tx time (µs)";
+
+    fn parse(lines: &[&str]) -> (Vec<Result<Option<SerialRecord>>>, Vec<String>) {
+        let mut parser = SerialParser::new();
+        let records = lines.iter().map(|line| parser.parse_line(line)).collect();
+        (records, parser.take_warnings())
+    }
+
+    fn neighbor(record: &Result<Option<SerialRecord>>) -> &NeighborStats {
+        match record {
+            Ok(Some(SerialRecord::NeighborStats(n))) => n,
+            other => panic!("not a neighbor_stats record: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn columns_are_found_by_name() {
+        let (records, warnings) = parse(&[
+            &format!("1.0;m3-1;{}", NEIGHBOR_HEADER),
+            "1.0;m3-1;neighbor_stats,2a:ab,1,1.5,10,20,-60,255,1200",
+            /* The same columns, reordered */
+            "1.0;m3-2;neighbor_stats,sent,received,L2 address,fresh,etx,lqi,rssi (dBm),avg tx time (µs)",
+            "1.0;m3-2;neighbor_stats,11,21,2a:ac,0,2.25,254,-61,1300",
+        ]);
+        assert!(records[0].as_ref().unwrap().is_none());
+        let (first, second) = (neighbor(&records[1]), neighbor(&records[3]));
+        assert_eq!(
+            (first.l2_address.as_str(), first.sent, first.rssi),
+            ("2a:ab", 10, -60)
+        );
+        assert_eq!(
+            (
+                second.l2_address.as_str(),
+                second.sent,
+                second.received,
+                second.rssi,
+                second.lqi
+            ),
+            ("2a:ac", 11, 21, -61, 254)
+        );
+        assert_eq!(second.avg_tx_time, 1300);
+        /* The second header differs from the first one */
+        assert_eq!(warnings.len(), 1);
+    }
+
+    #[test]
+    fn renamed_columns_are_read_by_position() {
+        let (records, warnings) = parse(&[
+            "1.0;m3-1;neighbor_stats,L2 address,fresh,etx,sent,received,rssi (dBm),lqi,avg tx time (us)",
+            "1.0;m3-1;neighbor_stats,2a:ab,1,1.5,10,20,-60,255,1200",
+            "1.0;m3-2;neighbor_stats,L2 address,fresh,etx,sent,received,rssi (dBm),lqi,avg tx time (us)",
+            "1.0;m3-2;neighbor_stats,2a:ac,1,1.5,11,21,-61,254,1300",
+        ]);
+        assert_eq!(neighbor(&records[1]).avg_tx_time, 1200);
+        assert_eq!(neighbor(&records[3]).avg_tx_time, 1300);
+        /* Once for the tag, whatever the number of nodes */
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert!(warnings[0].contains("avg tx time (us)"));
+    }
+
+    #[test]
+    fn swapped_names_are_not_read_by_position() {
+        /* The field at the default position of "sent" is another known column */
+        let (records, _) = parse(&[
+            "1.0;m3-1;neighbor_stats,L2 address,fresh,etx,received,received,rssi (dBm),lqi,avg tx time (µs)",
+            "1.0;m3-1;neighbor_stats,2a:ab,1,1.5,10,20,-60,255,1200",
+        ]);
+        let error = records[1].as_ref().unwrap_err();
+        assert_eq!(error.kind.name(), "missing_field");
+    }
+
+    fn names(header: &[&str], count: usize) -> Vec<String> {
+        let header: Vec<String> = header.iter().map(|s| s.to_string()).collect();
+        column_names(&header, count)
+    }
+
+    #[test]
+    fn column_names_are_unique() {
+        assert_eq!(names(&["a", "b"], 3), ["a", "b", "field 3"]);
+        assert_eq!(names(&["a", "a", "A"], 3), ["a", "a 2", "A 3"]);
+        assert_eq!(
+            names(&["Node", "node", "Timestamp"], 3),
+            ["Node 2", "node 3", "Timestamp 2"]
+        );
+        assert_eq!(names(&[" ", "field 1"], 2), ["field 1", "field 1 2"]);
+        assert_eq!(names(&[], 2), ["field 1", "field 2"]);
+    }
+
+    fn generic(record: &Result<Option<SerialRecord>>) -> &Generic {
+        match record {
+            Ok(Some(SerialRecord::Generic(g))) => g,
+            other => panic!("not a generic record: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unknown_tags_get_a_table() {
+        let (records, _) = parse(&[
+            "1.0;m3-1;bar,Node,value",
+            "1.0;m3-1;bar,5,6.5,extra",
+            /* The first line of baz captured by the aggregator is data */
+            "1.0;m3-1;baz,1,2",
+            "1.0;m3-1;baz,3,4",
+        ]);
+        let bar = generic(&records[1]);
+        assert_eq!(bar.header, ["Node 2", "value", "field 3"]);
+        assert_eq!(
+            bar.values,
+            [Value::Integer(5), Value::Real(6.5), Value::from("extra")]
+        );
+        let baz = generic(&records[3]);
+        assert_eq!(baz.header, ["field 1", "field 2"]);
+        assert_eq!(baz.values, [Value::Integer(3), Value::Integer(4)]);
+    }
+
+    fn nanos(epoch: &str) -> i64 {
+        parse_timestamp(epoch).unwrap().timestamp_nanos()
+    }
+
+    #[test]
+    fn timestamp_keeps_nanoseconds() {
+        assert_eq!(nanos("1614000000"), 1_614_000_000_000_000_000);
+        assert_eq!(nanos("1614000000.123456789"), 1_614_000_000_123_456_789);
+        assert_eq!(nanos("1614000000.5"), 1_614_000_000_500_000_000);
+        assert_eq!(nanos(" 1614000000.000001 "), 1_614_000_000_000_001_000);
+        /* Digits past the nanosecond are validated, then dropped */
+        assert_eq!(nanos("1.1234567899999999999999"), 1_123_456_789);
+    }
+
+    #[test]
+    fn timestamp_negative_epochs() {
+        assert_eq!(nanos("-1"), -1_000_000_000);
+        assert_eq!(nanos("-1.25"), -1_250_000_000);
+        assert_eq!(nanos("-0.5"), -500_000_000);
+    }
+
+    #[test]
+    fn timestamp_rejects_signed_fraction() {
+        for epoch in &["1.+5", "1.-5", "1. 5", "1.5x", "", "abc", "1.2.3"] {
+            let error = parse_timestamp(epoch).unwrap_err();
+            assert_eq!(error.kind.name(), "invalid_integer", "{:?}", epoch);
+        }
+    }
+
+    #[test]
+    fn timestamp_rejects_out_of_range() {
+        for epoch in &[
+            "99999999999",
+            "9999999999999999",
+            "-9999999999",
+            "-9223372036854775808",
+        ] {
+            let error = parse_timestamp(epoch).unwrap_err();
+            assert_eq!(error.kind.name(), "invalid_timestamp", "{:?}", epoch);
+        }
+        /* The limits of what chrono converts to nanoseconds in an i64 */
+        assert_eq!(nanos("9223372036.854775807"), i64::MAX);
+        assert!(parse_timestamp("9223372036.854775808").is_err());
+        assert_eq!(nanos("-9223372036"), -9_223_372_036_000_000_000);
+        assert!(parse_timestamp("-9223372036.000000001").is_err());
+    }
+
+    #[test]
+    fn server_line_rejects_out_of_range() {
+        let line = "2300-01-01 00:00:00.000000+00:00,2001:db8::1,1234,abc";
+        let error = parse_server_line(line).unwrap_err();
+        assert_eq!(error.kind.name(), "invalid_timestamp");
+        let line = "2021-02-22 13:20:00.5+00:00,2001:db8::1,1234,abc";
+        assert!(parse_server_line(line).is_ok());
+    }
+}
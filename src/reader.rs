@@ -0,0 +1,248 @@
+use crate::error::{Error, Result};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+
+/// A line of the input, without its terminator
+pub struct Line<'a> {
+    /// Line number, starting from 1
+    pub number: u64,
+    /// Byte offset of the start of the line in the decompressed input
+    pub offset: u64,
+    pub bytes: &'a [u8],
+    /// Whether the line is longer than the limit of the reader, in which
+    /// case `bytes` only holds its beginning
+    pub truncated: bool,
+}
+
+/// Default limit of the length of a line, beyond which the rest of the line
+/// is skipped
+pub const MAX_LINE_LENGTH: usize = 4 << 20;
+
+/// Reads the input one line at a time, whatever the size of the underlying
+/// reads, so that only the current line is held in memory.
+///
+/// Lines end with `\n` or `\r\n`; the last line is returned even when the
+/// input does not end with a newline. Only the first `max_length` bytes of a
+/// line are kept, so that garbage without newlines cannot exhaust the memory.
+pub struct LineReader<R> {
+    reader: R,
+    buffer: Vec<u8>,
+    number: u64,
+    offset: u64,
+    max_length: usize,
+}
+
+impl<R: BufRead> LineReader<R> {
+    pub fn new(reader: R) -> Self {
+        LineReader {
+            reader,
+            buffer: Vec::new(),
+            number: 0,
+            offset: 0,
+            max_length: MAX_LINE_LENGTH,
+        }
+    }
+
+    /// Sets the length of the lines beyond which their rest is skipped
+    pub fn with_max_length(mut self, max_length: usize) -> Self {
+        self.max_length = max_length;
+        self
+    }
+
+    /// Number of bytes consumed so far
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Number of lines read so far
+    pub fn line_number(&self) -> u64 {
+        self.number
+    }
+
+    /// The bytes of the line being read when `next_line` failed
+    pub fn remainder(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    /// The next line, or `None` at the end of the input
+    pub fn next_line(&mut self) -> io::Result<Option<Line<'_>>> {
+        self.buffer.clear();
+        /* Room for the terminator, which is not part of the length */
+        let capacity = self.max_length.saturating_add(2);
+        let mut n = 0;
+        loop {
+            let available = match self.reader.fill_buf() {
+                Ok(available) => available,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            let (chunk, end) = match available.iter().position(|&b| b == b'\n') {
+                Some(i) => (&available[..=i], true),
+                None => (available, available.is_empty()),
+            };
+            let room = capacity - self.buffer.len();
+            self.buffer
+                .extend_from_slice(&chunk[..chunk.len().min(room)]);
+            let used = chunk.len();
+            self.reader.consume(used);
+            n += used;
+            if end {
+                break;
+            }
+        }
+        if n == 0 {
+            return Ok(None);
+        }
+        let offset = self.offset;
+        self.offset += n as u64;
+        self.number += 1;
+
+        let mut bytes = &self.buffer[..];
+        if let Some(stripped) = bytes.strip_suffix(b"\n") {
+            bytes = stripped.strip_suffix(b"\r").unwrap_or(stripped);
+        }
+        let truncated = bytes.len() > self.max_length;
+        Ok(Some(Line {
+            number: self.number,
+            offset,
+            bytes: &bytes[..bytes.len().min(self.max_length)],
+            truncated,
+        }))
+    }
+}
+
+/// The compression formats of the input, told apart by their magic bytes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Plain,
+    Gzip,
+    Xz,
+    Lzma,
+    Bzip2,
+    Zstd,
+}
+
+impl Format {
+    /// The format of the data starting with `magic`
+    pub fn detect(magic: &[u8]) -> Format {
+        match magic {
+            [0x28, 0xb5, 0x2f, 0xfd, ..] => Format::Zstd,
+            [0x1f, 0x8b, ..] => Format::Gzip,
+            [0xfd, b'7', b'z', b'X', b'Z', 0x00, ..] => Format::Xz,
+            [b'B', b'Z', b'h', ..] => Format::Bzip2,
+            /* Legacy .lzma files have no magic, but start with the default properties */
+            [0x5d, 0x00, 0x00, ..] => Format::Lzma,
+            _ => Format::Plain,
+        }
+    }
+
+    /// Wraps `reader` into the matching decoder
+    fn decoder<'a, R: BufRead + 'a>(self, reader: R) -> io::Result<Box<dyn BufRead + 'a>> {
+        Ok(match self {
+            Format::Plain => Box::new(reader),
+            Format::Gzip => Box::new(BufReader::new(flate2::bufread::MultiGzDecoder::new(reader))),
+            Format::Xz => Box::new(BufReader::new(xz2::bufread::XzDecoder::new_multi_decoder(
+                reader,
+            ))),
+            Format::Lzma => {
+                let stream = xz2::stream::Stream::new_lzma_decoder(u64::MAX)?;
+                Box::new(BufReader::new(xz2::bufread::XzDecoder::new_stream(
+                    reader, stream,
+                )))
+            }
+            Format::Bzip2 => Box::new(BufReader::new(bzip2::bufread::MultiBzDecoder::new(reader))),
+            Format::Zstd => Box::new(BufReader::new(zstd::stream::Decoder::with_buffer(reader)?)),
+        })
+    }
+}
+
+/// Opens `filename`, or the standard input for `-`, for reading line by
+/// line, decompressing it according to its magic bytes, which tell the
+/// returned format
+pub fn open(filename: &str) -> Result<(LineReader<Box<dyn BufRead>>, Format)> {
+    let in_file = |e: io::Error| Error::from(e).in_file(filename);
+    let mut reader: Box<dyn BufRead> = if filename == "-" {
+        Box::new(BufReader::new(io::stdin()))
+    } else {
+        Box::new(BufReader::new(File::open(filename).map_err(in_file)?))
+    };
+    /* Peek at the magic bytes without consuming them */
+    let format = Format::detect(reader.fill_buf().map_err(in_file)?);
+    Ok((
+        LineReader::new(format.decoder(reader).map_err(in_file)?),
+        format,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The lines of `input`, as `(number, offset, bytes, truncated)`
+    fn lines(input: &[u8], max_length: usize) -> Vec<(u64, u64, Vec<u8>, bool)> {
+        /* A tiny buffer, so that lines span several reads */
+        let mut reader =
+            LineReader::new(BufReader::with_capacity(3, input)).with_max_length(max_length);
+        let mut lines = Vec::new();
+        while let Some(line) = reader.next_line().unwrap() {
+            lines.push((
+                line.number,
+                line.offset,
+                line.bytes.to_vec(),
+                line.truncated,
+            ));
+        }
+        assert_eq!(reader.offset(), input.len() as u64);
+        lines
+    }
+
+    #[test]
+    fn long_lines_are_cut() {
+        assert_eq!(
+            lines(b"abcd\r\nabcdefgh\nabcde\nab", 4),
+            [
+                (1, 0, b"abcd".to_vec(), false),
+                (2, 6, b"abcd".to_vec(), true),
+                (3, 15, b"abcd".to_vec(), true),
+                (4, 21, b"ab".to_vec(), false),
+            ]
+        );
+        assert_eq!(lines(b"abcdefgh", 4), [(1, 0, b"abcd".to_vec(), true)]);
+    }
+
+    #[test]
+    fn crlf_and_missing_newline() {
+        assert_eq!(
+            lines(b"ab\r\ncd\n\r\nef", MAX_LINE_LENGTH),
+            [
+                (1, 0, b"ab".to_vec(), false),
+                (2, 4, b"cd".to_vec(), false),
+                (3, 7, b"".to_vec(), false),
+                (4, 9, b"ef".to_vec(), false),
+            ]
+        );
+        /* A lone carriage return is part of the line */
+        assert_eq!(
+            lines(b"a\rb\r", MAX_LINE_LENGTH),
+            [(1, 0, b"a\rb\r".to_vec(), false)]
+        );
+        assert_eq!(lines(b"\n", MAX_LINE_LENGTH), [(1, 0, b"".to_vec(), false)]);
+        assert!(lines(b"", MAX_LINE_LENGTH).is_empty());
+    }
+
+    #[test]
+    fn formats_are_detected() {
+        assert_eq!(
+            Format::detect(&[0x28, 0xb5, 0x2f, 0xfd, 0x04]),
+            Format::Zstd
+        );
+        assert_eq!(Format::detect(&[0x1f, 0x8b, 0x08]), Format::Gzip);
+        assert_eq!(Format::detect(b"\xfd7zXZ\x00\x00"), Format::Xz);
+        assert_eq!(Format::detect(b"BZh91AY"), Format::Bzip2);
+        assert_eq!(Format::detect(&[0x5d, 0x00, 0x00, 0x80]), Format::Lzma);
+        assert_eq!(Format::detect(b"1612345678.1;m3-1;stats"), Format::Plain);
+        /* Too short to hold a magic */
+        assert_eq!(Format::detect(&[0x28, 0xb5]), Format::Plain);
+        assert_eq!(Format::detect(b""), Format::Plain);
+    }
+}
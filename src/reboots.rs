@@ -0,0 +1,169 @@
+use crate::counters::{available_counters, COUNTERS};
+use crate::error::Result;
+use crate::migrations::columns;
+use crate::report::format_timestamp;
+use crate::sqlite::quote;
+use rusqlite::{params, Connection};
+use std::collections::HashMap;
+
+/// The tables of serial rows, i.e. the ones with a node and a timestamp
+fn serial_tables(connection: &Connection) -> Result<Vec<String>> {
+    let tables: Vec<String> = connection
+        .prepare(
+            "SELECT name FROM sqlite_master
+             WHERE type = 'table' AND name != 'node_reboots' AND name NOT LIKE 'sqlite_%'",
+        )?
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    let mut serial = Vec::new();
+    for table in tables {
+        let columns = columns(connection, &table)?;
+        if columns.iter().any(|c| c == "Node") && columns.iter().any(|c| c == "ts_ns") {
+            /* Tables of tags unknown to the schema are created without it */
+            if !columns.iter().any(|c| c == "boot") {
+                connection.execute_batch(&format!(
+                    r#"ALTER TABLE {} ADD COLUMN "boot" INTEGER;"#,
+                    quote(&table)
+                ))?;
+            }
+            serial.push(table);
+        }
+    }
+    Ok(serial)
+}
+
+/// Numbers the boots of the nodes in `node_reboots`, and tags the rows of
+/// the serial tables with the boot they were printed in
+fn number_boots(connection: &Connection, experiment_id: i64, tables: &[String]) -> Result<()> {
+    let boot = r#"(SELECT count(*) FROM "node_reboots" r
+                   WHERE r."experiment_id" = t."experiment_id" AND r."Node" = t."Node"
+                   AND r."ts_ns" <= t."ts_ns")"#;
+    for table in tables
+        .iter()
+        .map(String::as_str)
+        .chain(Some("node_reboots"))
+    {
+        connection.execute(
+            &format!(
+                r#"UPDATE {} AS t SET "boot" = {} WHERE "experiment_id" = ?"#,
+                quote(table),
+                boot
+            ),
+            [experiment_id],
+        )?;
+    }
+    Ok(())
+}
+
+/// The samples of the counters of `table` lower than the previous sample
+/// of the same node, key and boot: `(node, previous time, time)`
+fn counter_decreases(
+    connection: &Connection,
+    experiment_id: i64,
+    table: &str,
+    keys: &[&str],
+    counters: &[&str],
+) -> Result<Vec<(String, i64, i64)>> {
+    let counters = match available_counters(connection, table, keys, counters)? {
+        Some(counters) => counters,
+        None => return Ok(Vec::new()),
+    };
+    let partition: Vec<String> = keys.iter().map(|k| quote(k)).collect();
+    let lags: Vec<String> = counters
+        .iter()
+        .enumerate()
+        .map(|(i, c)| format!("{0}, LAG({0}) OVER w AS \"previous {1}\"", quote(c), i))
+        .collect();
+    let decreases: Vec<String> = counters
+        .iter()
+        .enumerate()
+        .map(|(i, c)| format!("{} < \"previous {}\"", quote(c), i))
+        .collect();
+    let query = format!(
+        r#"SELECT "Node", "previous ts_ns", "ts_ns" FROM (
+             SELECT "Node", "ts_ns", "boot", {lags},
+                    LAG("ts_ns") OVER w AS "previous ts_ns", LAG("boot") OVER w AS "previous boot"
+             FROM {table} WHERE "experiment_id" = ? AND "ts_ns" IS NOT NULL
+             WINDOW w AS (PARTITION BY "Node", {partition} ORDER BY "ts_ns")
+           ) WHERE "boot" = "previous boot" AND ({decreases})"#,
+        lags = lags.join(", "),
+        table = quote(table),
+        partition = partition.join(", "),
+        decreases = decreases.join(" OR ")
+    );
+    let rows = connection
+        .prepare(&query)?
+        .query_map([experiment_id], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(rows)
+}
+
+/// Completes the reboots detected while parsing the serial output with the
+/// ones revealed by a decrease of the cumulative counters of `stats` and
+/// `rpl_stats`, and tags every serial row with the boot of its node it was
+/// printed in, counting from 0.
+///
+/// The counters of `neighbor_stats` are not used: they also restart when
+/// the entry of the neighbor is evicted from the neighbor table.
+///
+/// A decrease between two samples is explained by any reboot between them;
+/// otherwise a reboot, of cause `counter_reset`, is dated by the first
+/// sample after the decrease.
+pub fn detect_reboots(connection: &Connection, experiment_id: i64) -> Result<()> {
+    connection.execute(
+        r#"DELETE FROM "node_reboots" WHERE "experiment_id" = ? AND "cause" = 'counter_reset'"#,
+        [experiment_id],
+    )?;
+    let tables = serial_tables(connection)?;
+    number_boots(connection, experiment_id, &tables)?;
+
+    let mut decreases = Vec::new();
+    for (table, keys, counters) in COUNTERS.iter().filter(|(t, _, _)| *t != "neighbor_stats") {
+        decreases.extend(counter_decreases(
+            connection,
+            experiment_id,
+            table,
+            keys,
+            counters,
+        )?);
+    }
+    decreases.sort_by_key(|(_, _, ts_ns)| *ts_ns);
+    let mut resets: HashMap<&str, Vec<i64>> = HashMap::new();
+    for (node, previous, ts_ns) in &decreases {
+        let known = resets.entry(node).or_default();
+        if !known.iter().any(|t| previous < t && t <= ts_ns) {
+            known.push(*ts_ns);
+        }
+    }
+
+    let mut insert = connection.prepare(
+        r#"INSERT INTO "node_reboots" ("Timestamp", "Node", "cause", "experiment_id", "ts_ns")
+           VALUES (?, ?, 'counter_reset', ?, ?)"#,
+    )?;
+    for (node, times) in &resets {
+        for ts_ns in times {
+            insert.execute(params![
+                format_timestamp(*ts_ns),
+                node,
+                experiment_id,
+                ts_ns
+            ])?;
+        }
+    }
+    number_boots(connection, experiment_id, &tables)?;
+
+    let (logged, reset): (i64, i64) = connection.query_row(
+        r#"SELECT count(*) - coalesce(sum("cause" = 'counter_reset'), 0),
+                  coalesce(sum("cause" = 'counter_reset'), 0)
+           FROM "node_reboots" WHERE "experiment_id" = ?"#,
+        [experiment_id],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    println!(
+        "{} reboots detected in the serial output, {} from counter resets",
+        logged, reset
+    );
+    Ok(())
+}
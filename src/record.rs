@@ -0,0 +1,590 @@
+use crate::error::Error;
+use chrono::{DateTime, Utc};
+
+/// One row of the `neighbor_stats` output
+#[derive(Debug, Clone, PartialEq)]
+pub struct NeighborStats {
+    pub timestamp: DateTime<Utc>,
+    pub node: String,
+    pub l2_address: String,
+    pub fresh: String,
+    pub etx: String,
+    pub sent: i64,
+    pub received: i64,
+    pub rssi: i64,
+    pub lqi: i64,
+    pub avg_tx_time: i64,
+}
+
+/// One row of the `rpl_stats` output
+#[derive(Debug, Clone, PartialEq)]
+pub struct RplStats {
+    pub timestamp: DateTime<Utc>,
+    pub node: String,
+    pub packet_type: String,
+    pub measurement_type: String,
+    pub rx_unicast: i64,
+    pub tx_unicast: i64,
+    pub rx_multicast: i64,
+    pub tx_multicast: i64,
+}
+
+/// One row of the `rpl_stats_dodag` output
+#[derive(Debug, Clone, PartialEq)]
+pub struct RplStatsDodag {
+    pub timestamp: DateTime<Utc>,
+    pub node: String,
+    pub instance_id: String,
+    pub ipv6_address: String,
+    pub rank: i64,
+    pub role: String,
+    pub prefix_information: String,
+    pub trickle_interval_size_min: i64,
+    pub trickle_interval_size_max: i64,
+    pub trickle_redundancy_constant: i64,
+    pub trickle_counter: i64,
+    pub trickle_tc: i64,
+}
+
+/// One row of the `rpl_stats_instance` output
+#[derive(Debug, Clone, PartialEq)]
+pub struct RplStatsInstance {
+    pub timestamp: DateTime<Utc>,
+    pub node: String,
+    pub instance_id: String,
+    pub interface_id: String,
+    pub mode_of_operation: String,
+    pub objective_code_point: String,
+    pub min_hop_rank_increase: String,
+    pub max_rank_increase: String,
+}
+
+/// One row of the `rpl_stats_parent` output
+#[derive(Debug, Clone, PartialEq)]
+pub struct RplStatsParent {
+    pub timestamp: DateTime<Utc>,
+    pub node: String,
+    pub instance_id: String,
+    pub ipv6_address: String,
+    pub rank: String,
+}
+
+/// One row of the `rpl_status` output
+#[derive(Debug, Clone, PartialEq)]
+pub struct RplStatus {
+    pub timestamp: DateTime<Utc>,
+    pub node: String,
+    pub table_type: String,
+    pub table_index: String,
+    pub table_status: String,
+}
+
+/// One row of the `stats` output
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats {
+    pub timestamp: DateTime<Utc>,
+    pub node: String,
+    pub success: i64,
+    pub layer: String,
+    pub rx_packets: i64,
+    pub rx_bytes: i64,
+    pub tx_packets: i64,
+    pub tx_multicast_packets: i64,
+    pub tx_bytes: i64,
+    pub tx_succeeded: i64,
+    pub tx_errors: i64,
+}
+
+/// A UDP packet sent by a node
+#[derive(Debug, Clone, PartialEq)]
+pub struct Udp {
+    pub timestamp: DateTime<Utc>,
+    pub node: String,
+    pub payload_size: String,
+    pub destination_address: String,
+    pub destination_port: String,
+    pub payload: String,
+}
+
+/// An `info` message printed by a node
+#[derive(Debug, Clone, PartialEq)]
+pub struct Info {
+    pub timestamp: DateTime<Utc>,
+    pub node: String,
+    pub message: String,
+}
+
+/// Any other unformatted output of a node
+#[derive(Debug, Clone, PartialEq)]
+pub struct Output {
+    pub timestamp: DateTime<Utc>,
+    pub node: String,
+    pub stdout: String,
+}
+
+/// A reboot of a node, detected from the serial output: `cause` is `header`
+/// when it printed its header lines again, and `banner` when it printed its
+/// boot banner
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reboot {
+    pub timestamp: DateTime<Utc>,
+    pub node: String,
+    pub cause: String,
+}
+
+/// A row of a tag without a dedicated variant, or whose layout differs from
+/// the built-in one, described by the header the node printed for it or by
+/// the schema
+#[derive(Debug, Clone, PartialEq)]
+pub struct Generic {
+    pub timestamp: DateTime<Utc>,
+    pub node: String,
+    pub tag: String,
+    pub table: String,
+    pub header: Vec<String>,
+    pub values: Vec<Value>,
+}
+
+/// A record parsed from the serial aggregator output, one variant per tag
+#[derive(Debug, Clone, PartialEq)]
+pub enum SerialRecord {
+    NeighborStats(NeighborStats),
+    RplStats(RplStats),
+    RplStatsDodag(RplStatsDodag),
+    RplStatsInstance(RplStatsInstance),
+    RplStatsParent(RplStatsParent),
+    RplStatus(RplStatus),
+    Stats(Stats),
+    Udp(Udp),
+    Info(Info),
+    Output(Output),
+    Generic(Generic),
+    Reboot(Reboot),
+}
+
+impl SerialRecord {
+    /// The tag of the serial line this record was parsed from
+    pub fn tag(&self) -> &str {
+        match self {
+            SerialRecord::NeighborStats(_) => "neighbor_stats",
+            SerialRecord::RplStats(_) => "rpl_stats",
+            SerialRecord::RplStatsDodag(_) => "rpl_stats_dodag",
+            SerialRecord::RplStatsInstance(_) => "rpl_stats_instance",
+            SerialRecord::RplStatsParent(_) => "rpl_stats_parent",
+            SerialRecord::RplStatus(_) => "rpl_status",
+            SerialRecord::Stats(_) => "stats",
+            SerialRecord::Udp(_) => "udp",
+            SerialRecord::Info(_) => "info",
+            SerialRecord::Output(_) => "output",
+            SerialRecord::Generic(r) => &r.tag,
+            SerialRecord::Reboot(_) => "reboot",
+        }
+    }
+
+    /// The table the record is stored into
+    pub fn table(&self) -> &str {
+        match self {
+            SerialRecord::Generic(r) => &r.table,
+            SerialRecord::Reboot(_) => "node_reboots",
+            _ => self.tag(),
+        }
+    }
+
+    pub fn timestamp(&self) -> &DateTime<Utc> {
+        match self {
+            SerialRecord::NeighborStats(r) => &r.timestamp,
+            SerialRecord::RplStats(r) => &r.timestamp,
+            SerialRecord::RplStatsDodag(r) => &r.timestamp,
+            SerialRecord::RplStatsInstance(r) => &r.timestamp,
+            SerialRecord::RplStatsParent(r) => &r.timestamp,
+            SerialRecord::RplStatus(r) => &r.timestamp,
+            SerialRecord::Stats(r) => &r.timestamp,
+            SerialRecord::Udp(r) => &r.timestamp,
+            SerialRecord::Info(r) => &r.timestamp,
+            SerialRecord::Output(r) => &r.timestamp,
+            SerialRecord::Generic(r) => &r.timestamp,
+            SerialRecord::Reboot(r) => &r.timestamp,
+        }
+    }
+
+    /// The column names of the records of a tag, starting with `Timestamp` and `Node`
+    pub fn columns_for(tag: &str) -> Option<&'static [&'static str]> {
+        let columns: &'static [&'static str] = match tag {
+            "neighbor_stats" => &[
+                "Timestamp",
+                "Node",
+                "L2 address",
+                "fresh",
+                "etx",
+                "sent",
+                "received",
+                "rssi (dBm)",
+                "lqi",
+                "avg tx time (µs)",
+            ],
+            "rpl_stats" => &[
+                "Timestamp",
+                "Node",
+                "Packet Type",
+                "Measurement Type",
+                "RX unicast",
+                "TX unicast",
+                "RX multicast",
+                "TX multicast",
+            ],
+            "rpl_stats_dodag" => &[
+                "Timestamp",
+                "Node",
+                "Instance ID",
+                "IPv6 Adress",
+                "Rank",
+                "Role",
+                "Prefix Information",
+                "Trickle Interval Size Min",
+                "Trickle Interval Size Max",
+                "Trickle Redundancy Constant",
+                "Trickle Counter",
+                "Trickle TC",
+            ],
+            "rpl_stats_instance" => &[
+                "Timestamp",
+                "Node",
+                "Instance ID",
+                "Interface ID",
+                "Mode of Operation",
+                "Objective Code Point",
+                "Min Hop Rank Increase",
+                "Max Rank Increase",
+            ],
+            "rpl_stats_parent" => &["Timestamp", "Node", "Instance ID", "IPv6 Adress", "Rank"],
+            "rpl_status" => &[
+                "Timestamp",
+                "Node",
+                "Type of table",
+                "Index of the table",
+                "Table status",
+            ],
+            "stats" => &[
+                "Timestamp",
+                "Node",
+                "success",
+                "layer",
+                "rx packets",
+                "rx bytes",
+                "tx packets",
+                "tx multicast packets",
+                "tx bytes",
+                "tx succeeded",
+                "tx errors",
+            ],
+            "udp" => &[
+                "Timestamp",
+                "Node",
+                "payload size",
+                "destination address",
+                "destination port",
+                "payload",
+            ],
+            "info" => &["Timestamp", "Node", "Message"],
+            "output" => &["Timestamp", "Node", "Output Stdout"],
+            "reboot" => &["Timestamp", "Node", "cause"],
+            _ => return None,
+        };
+        Some(columns)
+    }
+
+    /// The column names of the record, matching `values`: the columns of
+    /// its tag, then `ts_ns`, the timestamp in nanoseconds since the epoch
+    pub fn columns(&self) -> Vec<&str> {
+        match self {
+            SerialRecord::Generic(r) => ["Timestamp", "Node"]
+                .iter()
+                .copied()
+                .chain(r.header.iter().map(|c| c.as_str()))
+                .chain(std::iter::once("ts_ns"))
+                .collect(),
+            _ => Self::columns_for(self.tag())
+                .expect("every record tag has columns")
+                .iter()
+                .copied()
+                .chain(std::iter::once("ts_ns"))
+                .collect(),
+        }
+    }
+
+    /// The field values of the record, in the order of `columns`
+    pub fn values(&self) -> Vec<Value> {
+        let timestamp = Value::Text(self.timestamp().to_string());
+        let node = Value::from(self.node());
+        let mut values = vec![timestamp, node];
+        match self {
+            SerialRecord::NeighborStats(r) => values.extend(vec![
+                Value::from(&r.l2_address),
+                Value::from(&r.fresh),
+                Value::from(&r.etx),
+                Value::from(r.sent),
+                Value::from(r.received),
+                Value::from(r.rssi),
+                Value::from(r.lqi),
+                Value::from(r.avg_tx_time),
+            ]),
+            SerialRecord::RplStats(r) => values.extend(vec![
+                Value::from(&r.packet_type),
+                Value::from(&r.measurement_type),
+                Value::from(r.rx_unicast),
+                Value::from(r.tx_unicast),
+                Value::from(r.rx_multicast),
+                Value::from(r.tx_multicast),
+            ]),
+            SerialRecord::RplStatsDodag(r) => values.extend(vec![
+                Value::from(&r.instance_id),
+                Value::from(&r.ipv6_address),
+                Value::from(r.rank),
+                Value::from(&r.role),
+                Value::from(&r.prefix_information),
+                Value::from(r.trickle_interval_size_min),
+                Value::from(r.trickle_interval_size_max),
+                Value::from(r.trickle_redundancy_constant),
+                Value::from(r.trickle_counter),
+                Value::from(r.trickle_tc),
+            ]),
+            SerialRecord::RplStatsInstance(r) => values.extend(vec![
+                Value::from(&r.instance_id),
+                Value::from(&r.interface_id),
+                Value::from(&r.mode_of_operation),
+                Value::from(&r.objective_code_point),
+                Value::from(&r.min_hop_rank_increase),
+                Value::from(&r.max_rank_increase),
+            ]),
+            SerialRecord::RplStatsParent(r) => values.extend(vec![
+                Value::from(&r.instance_id),
+                Value::from(&r.ipv6_address),
+                Value::from(&r.rank),
+            ]),
+            SerialRecord::RplStatus(r) => values.extend(vec![
+                Value::from(&r.table_type),
+                Value::from(&r.table_index),
+                Value::from(&r.table_status),
+            ]),
+            SerialRecord::Stats(r) => values.extend(vec![
+                Value::from(r.success),
+                Value::from(&r.layer),
+                Value::from(r.rx_packets),
+                Value::from(r.rx_bytes),
+                Value::from(r.tx_packets),
+                Value::from(r.tx_multicast_packets),
+                Value::from(r.tx_bytes),
+                Value::from(r.tx_succeeded),
+                Value::from(r.tx_errors),
+            ]),
+            SerialRecord::Udp(r) => values.extend(vec![
+                Value::from(&r.payload_size),
+                Value::from(&r.destination_address),
+                Value::from(&r.destination_port),
+                Value::from(&r.payload),
+            ]),
+            SerialRecord::Info(r) => values.push(Value::from(&r.message)),
+            SerialRecord::Output(r) => values.push(Value::from(&r.stdout)),
+            SerialRecord::Reboot(r) => values.push(Value::from(&r.cause)),
+            SerialRecord::Generic(r) => values.extend(r.values.iter().cloned()),
+        }
+        values.push(Value::Integer(self.timestamp().timestamp_nanos()));
+        values
+    }
+
+    pub fn node(&self) -> &str {
+        match self {
+            SerialRecord::NeighborStats(r) => &r.node,
+            SerialRecord::RplStats(r) => &r.node,
+            SerialRecord::RplStatsDodag(r) => &r.node,
+            SerialRecord::RplStatsInstance(r) => &r.node,
+            SerialRecord::RplStatsParent(r) => &r.node,
+            SerialRecord::RplStatus(r) => &r.node,
+            SerialRecord::Stats(r) => &r.node,
+            SerialRecord::Udp(r) => &r.node,
+            SerialRecord::Info(r) => &r.node,
+            SerialRecord::Output(r) => &r.node,
+            SerialRecord::Generic(r) => &r.node,
+            SerialRecord::Reboot(r) => &r.node,
+        }
+    }
+}
+
+/// A UDP packet received by the server
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerRecord {
+    pub timestamp: DateTime<Utc>,
+    pub ipv6_address: String,
+    pub port: i64,
+    pub payload: String,
+}
+
+impl ServerRecord {
+    /// The column names of the record, matching `values`
+    pub fn columns(&self) -> Vec<&str> {
+        vec![
+            "Timestamp",
+            "IPv6 Adress",
+            "receiver port",
+            "payload",
+            "ts_ns",
+        ]
+    }
+
+    /// The field values of the record, in the order of `columns`
+    pub fn values(&self) -> Vec<Value> {
+        vec![
+            Value::Text(self.timestamp.to_string()),
+            Value::from(&self.ipv6_address),
+            Value::from(self.port),
+            Value::from(&self.payload),
+            Value::Integer(self.timestamp.timestamp_nanos()),
+        ]
+    }
+}
+
+/// An input line that could not be turned into a record
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reject {
+    pub file: String,
+    pub line: u64,
+    pub offset: u64,
+    pub node: Option<String>,
+    pub tag: Option<String>,
+    pub raw: String,
+    /// The exact bytes of the line, when they are not valid UTF-8 and `raw`
+    /// is a lossy decoding of them
+    pub bytes: Option<Vec<u8>>,
+    pub kind: String,
+    pub message: String,
+}
+
+impl Reject {
+    /// Builds the reject of the line `raw` from the error raised while parsing it
+    pub fn new(error: &Error, raw: &str, file: &str, line: u64, offset: u64) -> Self {
+        Reject {
+            file: file.to_string(),
+            line,
+            offset,
+            node: error.context.node.clone(),
+            tag: error.context.tag.clone(),
+            raw: raw.to_string(),
+            bytes: None,
+            kind: error.kind.name().to_string(),
+            message: error.to_string(),
+        }
+    }
+
+    /// Keeps the exact bytes of a line which is not valid UTF-8
+    pub fn with_bytes(mut self, bytes: &[u8]) -> Self {
+        self.bytes = Some(bytes.to_vec());
+        self
+    }
+
+    /// The column names of the record, matching `values`
+    pub fn columns(&self) -> Vec<&str> {
+        vec![
+            "Source File",
+            "Line Number",
+            "Byte Offset",
+            "Node",
+            "Tag",
+            "Raw Line",
+            "Raw Bytes",
+            "Error Kind",
+            "Error Message",
+        ]
+    }
+
+    /// The field values of the record, in the order of `columns`
+    pub fn values(&self) -> Vec<Value> {
+        let optional = |v: &Option<String>| v.as_ref().map_or(Value::Null, Value::from);
+        vec![
+            Value::from(&self.file),
+            Value::Integer(self.line as i64),
+            Value::Integer(self.offset as i64),
+            optional(&self.node),
+            optional(&self.tag),
+            Value::from(&self.raw),
+            self.bytes.clone().map_or(Value::Null, Value::Blob),
+            Value::from(&self.kind),
+            Value::from(&self.message),
+        ]
+    }
+}
+
+/// One ingestion run: a serial file and a server file, along with the
+/// free-form parameters the run was launched with
+#[derive(Debug, Clone, PartialEq)]
+pub struct Experiment {
+    pub name: String,
+    pub serial_file: String,
+    pub server_file: String,
+    pub parameters: Vec<(String, String)>,
+    /// Time span of the records of the experiment, filled in as they are written
+    pub start: Option<DateTime<Utc>>,
+    pub end: Option<DateTime<Utc>>,
+}
+
+impl Experiment {
+    pub fn new(name: &str, serial_file: &str, server_file: &str) -> Self {
+        Experiment {
+            name: name.to_string(),
+            serial_file: serial_file.to_string(),
+            server_file: server_file.to_string(),
+            parameters: Vec::new(),
+            start: None,
+            end: None,
+        }
+    }
+
+    /// Extends the time span of the experiment to `timestamp`
+    pub fn include(&mut self, timestamp: DateTime<Utc>) {
+        self.start = Some(self.start.map_or(timestamp, |start| start.min(timestamp)));
+        self.end = Some(self.end.map_or(timestamp, |end| end.max(timestamp)));
+    }
+
+    /// The parameters, as a JSON object
+    pub fn parameters_json(&self) -> String {
+        let parameters: serde_json::Map<String, serde_json::Value> = self
+            .parameters
+            .iter()
+            .map(|(key, value)| (key.clone(), serde_json::Value::from(value.as_str())))
+            .collect();
+        serde_json::Value::Object(parameters).to_string()
+    }
+}
+
+/// A single field value, as stored by a sink
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+impl From<i64> for Value {
+    fn from(v: i64) -> Value {
+        Value::Integer(v)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(v: f64) -> Value {
+        Value::Real(v)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(v: &str) -> Value {
+        Value::Text(v.to_string())
+    }
+}
+
+impl From<&String> for Value {
+    fn from(v: &String) -> Value {
+        Value::Text(v.clone())
+    }
+}
@@ -0,0 +1,572 @@
+use crate::analysis::select_experiments;
+use crate::error::{Error, ErrorKind, Result};
+use crate::latency::{node_latencies, LatencySummary};
+use crate::record::Value;
+use chrono::{TimeZone, Utc};
+use rusqlite::Connection;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::str::FromStr;
+
+/// The output formats of the reports
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// Aligned columns, for the terminal
+    Table,
+    Csv,
+    /// An array of objects, one per row
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> std::result::Result<OutputFormat, String> {
+        match format {
+            "table" => Ok(OutputFormat::Table),
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!(
+                "unknown format '{}', expected table, csv or json",
+                format
+            )),
+        }
+    }
+}
+
+/// The rows of a report, ready to be printed
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Report {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
+}
+
+impl Report {
+    pub fn new(columns: &[&str]) -> Report {
+        Report {
+            columns: columns.iter().map(|c| c.to_string()).collect(),
+            rows: Vec::new(),
+        }
+    }
+
+    /// Writes the report to `out` in the given format
+    pub fn write(&self, format: OutputFormat, out: &mut dyn Write) -> Result<()> {
+        match format {
+            OutputFormat::Table => self.write_table(out),
+            OutputFormat::Csv => self.write_csv(out),
+            OutputFormat::Json => self.write_json(out),
+        }
+    }
+
+    fn write_table(&self, out: &mut dyn Write) -> Result<()> {
+        let cell = |value: &Value| match value {
+            Value::Null => "-".to_string(),
+            Value::Real(v) => format!("{:.4}", v),
+            value => text(value),
+        };
+        let cells: Vec<Vec<String>> = self
+            .rows
+            .iter()
+            .map(|row| row.iter().map(cell).collect())
+            .collect();
+        let widths: Vec<usize> = self
+            .columns
+            .iter()
+            .enumerate()
+            .map(|(i, column)| {
+                cells
+                    .iter()
+                    .map(|row| row[i].chars().count())
+                    .chain(Some(column.chars().count()))
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect();
+
+        let header: Vec<String> = self
+            .columns
+            .iter()
+            .zip(&widths)
+            .map(|(column, width)| format!("{:<width$}", column, width = width))
+            .collect();
+        writeln!(out, "{}", header.join("  ").trim_end())?;
+        let rule: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+        writeln!(out, "{}", rule.join("  "))?;
+        for (row, values) in cells.iter().zip(&self.rows) {
+            /* Numbers are aligned on the right */
+            let line: Vec<String> = row
+                .iter()
+                .zip(values)
+                .zip(&widths)
+                .map(|((cell, value), width)| match value {
+                    Value::Integer(_) | Value::Real(_) => {
+                        format!("{:>width$}", cell, width = width)
+                    }
+                    _ => format!("{:<width$}", cell, width = width),
+                })
+                .collect();
+            writeln!(out, "{}", line.join("  ").trim_end())?;
+        }
+        Ok(())
+    }
+
+    fn write_csv(&self, out: &mut dyn Write) -> Result<()> {
+        let field = |field: &str| {
+            if field.contains(&[',', '"', '\n', '\r'][..]) {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field.to_string()
+            }
+        };
+        let header: Vec<String> = self.columns.iter().map(|c| field(c)).collect();
+        writeln!(out, "{}", header.join(","))?;
+        for row in &self.rows {
+            let line: Vec<String> = row.iter().map(|value| field(&text(value))).collect();
+            writeln!(out, "{}", line.join(","))?;
+        }
+        Ok(())
+    }
+
+    fn write_json(&self, out: &mut dyn Write) -> Result<()> {
+        let rows: Vec<serde_json::Value> = self
+            .rows
+            .iter()
+            .map(|row| {
+                self.columns
+                    .iter()
+                    .cloned()
+                    .zip(row.iter().map(json))
+                    .collect::<serde_json::Map<_, _>>()
+                    .into()
+            })
+            .collect();
+        writeln!(out, "{:#}", serde_json::Value::Array(rows))?;
+        Ok(())
+    }
+}
+
+/// The value as written in CSV files
+fn text(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::Integer(v) => v.to_string(),
+        Value::Real(v) => v.to_string(),
+        Value::Text(v) => v.clone(),
+        Value::Blob(v) => v.iter().map(|b| format!("{:02x}", b)).collect(),
+    }
+}
+
+fn json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Null => serde_json::Value::Null,
+        Value::Integer(v) => (*v).into(),
+        /* NaN and infinities are not valid JSON numbers, and become null */
+        Value::Real(v) => (*v).into(),
+        Value::Text(v) => v.as_str().into(),
+        Value::Blob(_) => text(value).into(),
+    }
+}
+
+/// The time of a timestamp in nanoseconds, as written in the data tables
+pub(crate) fn format_timestamp(ts_ns: i64) -> String {
+    Utc.timestamp_nanos(ts_ns).to_string()
+}
+
+/// Adds the packet delivery ratio row of `sent` packets with `delivered`
+/// ones, which has no ratio when no packet was sent
+fn pdr_row(report: &mut Report, row: Vec<Value>, sent: i64, delivered: i64) {
+    let mut row = row;
+    let ratio = if sent > 0 {
+        (delivered as f64 / sent as f64).into()
+    } else {
+        Value::Null
+    };
+    row.extend(vec![sent.into(), delivered.into(), ratio]);
+    report.rows.push(row);
+}
+
+/// The packet delivery ratio of the experiments named in `names` (all of
+/// them when empty): overall, per node, and over windows of `window_ns`
+/// nanoseconds starting at the first packet sent.
+///
+/// The overall and windowed rows have no node. The windows in which no packet
+/// was sent, between the first and the last one, have no ratio.
+pub fn pdr(connection: &Connection, names: &[String], window_ns: Option<i64>) -> Result<Report> {
+    let mut report = Report::new(&[
+        "experiment",
+        "node",
+        "window_start",
+        "sent",
+        "delivered",
+        "pdr",
+    ]);
+    let mut overall = connection.prepare(
+        r#"SELECT count(*), sum("delivered"), min("send_ts_ns")
+           FROM "deliveries" WHERE "experiment_id" = ?"#,
+    )?;
+    let mut per_node = connection.prepare(
+        r#"SELECT "node", count(*), sum("delivered")
+           FROM "deliveries" WHERE "experiment_id" = ?
+           GROUP BY "node" ORDER BY "node""#,
+    )?;
+    let mut per_window = connection.prepare(
+        r#"SELECT ("send_ts_ns" - ?2) / ?3, count(*), sum("delivered")
+           FROM "deliveries" WHERE "experiment_id" = ?1
+           GROUP BY 1 ORDER BY 1"#,
+    )?;
+
+    for (id, name) in select_experiments(connection, names)? {
+        let (sent, delivered, start): (i64, Option<i64>, Option<i64>) =
+            overall.query_row([id], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        if sent == 0 {
+            eprintln!("Warning: no packet sent in experiment {}", name);
+            continue;
+        }
+        pdr_row(
+            &mut report,
+            vec![(&name).into(), Value::Null, Value::Null],
+            sent,
+            delivered.unwrap_or(0),
+        );
+
+        let nodes = per_node
+            .query_map([id], |row| {
+                Ok((row.get::<_, String>(0)?, row.get(1)?, row.get(2)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for (node, sent, delivered) in nodes {
+            pdr_row(
+                &mut report,
+                vec![(&name).into(), (&node).into(), Value::Null],
+                sent,
+                delivered,
+            );
+        }
+
+        if let (Some(window), Some(start)) = (window_ns, start) {
+            let windows = per_window
+                .query_map(rusqlite::params![id, start, window], |row| {
+                    Ok((row.get::<_, i64>(0)?, row.get(1)?, row.get(2)?))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            /* The windows without any packet sent are not grouped, but reported */
+            let last = windows.last().map_or(0, |(index, _, _)| *index);
+            let mut windows = windows.into_iter().peekable();
+            for index in 0..=last {
+                let (sent, delivered) = match windows.next_if(|(i, _, _)| *i == index) {
+                    Some((_, sent, delivered)) => (sent, delivered),
+                    None => (0, 0),
+                };
+                let window_start = format_timestamp(start + index * window);
+                pdr_row(
+                    &mut report,
+                    vec![(&name).into(), Value::Null, (&window_start).into()],
+                    sent,
+                    delivered,
+                );
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// The columns of the latency statistics, in milliseconds
+const LATENCY_COLUMNS: &[&str] = &[
+    "packets",
+    "min_ms",
+    "mean_ms",
+    "median_ms",
+    "p95_ms",
+    "p99_ms",
+    "max_ms",
+];
+
+fn milliseconds(ns: i64) -> Value {
+    (ns as f64 / 1e6).into()
+}
+
+fn latency_values(summary: &LatencySummary) -> Vec<Value> {
+    vec![
+        (summary.packets as i64).into(),
+        milliseconds(summary.min_ns),
+        (summary.mean_ns / 1e6).into(),
+        milliseconds(summary.median_ns),
+        milliseconds(summary.p95_ns),
+        milliseconds(summary.p99_ns),
+        milliseconds(summary.max_ns),
+    ]
+}
+
+/// The latency statistics of the experiments named in `names` (all of them
+/// when empty), overall and per node, as stored in `latency_summary`
+pub fn latency(connection: &Connection, names: &[String]) -> Result<Report> {
+    let mut report = Report::new(&[&["experiment", "node"][..], LATENCY_COLUMNS].concat());
+    let mut statement = connection.prepare(
+        r#"SELECT "node", "packets", "min_ns", "mean_ns", "median_ns", "p95_ns", "p99_ns", "max_ns"
+           FROM "latency_summary" WHERE "experiment_id" = ?
+           ORDER BY "node" IS NOT NULL, "node""#,
+    )?;
+    for (id, name) in select_experiments(connection, names)? {
+        let rows = statement
+            .query_map([id], |row| {
+                let summary = LatencySummary {
+                    packets: row.get::<_, i64>(1)? as usize,
+                    min_ns: row.get(2)?,
+                    mean_ns: row.get(3)?,
+                    median_ns: row.get(4)?,
+                    p95_ns: row.get(5)?,
+                    p99_ns: row.get(6)?,
+                    max_ns: row.get(7)?,
+                };
+                Ok((row.get::<_, Option<String>>(0)?, summary))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for (node, summary) in rows {
+            let mut row = vec![
+                (&name).into(),
+                node.as_ref().map_or(Value::Null, Value::from),
+            ];
+            row.extend(latency_values(&summary));
+            report.rows.push(row);
+        }
+    }
+    Ok(report)
+}
+
+/// Largest number of bins of a latency histogram
+const MAX_BINS: i64 = 10_000;
+
+/// The histogram of the latencies of the experiments named in `names` (all
+/// of them when empty), with bins of `bin_ns` nanoseconds.
+///
+/// Every bin between the smallest and the largest latency is reported, the
+/// empty ones included. Bins so narrow that an experiment would need more
+/// than `MAX_BINS` of them are refused, with the smallest width allowed.
+pub fn latency_histogram(connection: &Connection, names: &[String], bin_ns: i64) -> Result<Report> {
+    let mut report = Report::new(&[
+        "experiment",
+        "bin_start_ms",
+        "bin_end_ms",
+        "packets",
+        "fraction",
+    ]);
+    for (id, name) in select_experiments(connection, names)? {
+        let mut bins: BTreeMap<i64, i64> = BTreeMap::new();
+        for (_, latencies) in node_latencies(connection, id)? {
+            for latency in latencies {
+                *bins.entry(latency.div_euclid(bin_ns)).or_default() += 1;
+            }
+        }
+        let total: i64 = bins.values().sum();
+        let (first, last) = match (bins.keys().next(), bins.keys().next_back()) {
+            (Some(first), Some(last)) => (*first, *last),
+            _ => continue,
+        };
+        if last - first >= MAX_BINS {
+            /* Any width larger than the span over MAX_BINS - 1 gives at most MAX_BINS bins */
+            let span = (last - first + 1).saturating_mul(bin_ns);
+            return Err(Error::new(ErrorKind::TooManyBins(
+                span / (MAX_BINS - 1) + 1,
+            )));
+        }
+        /* Empty bins are kept, so that the histogram can be plotted as is */
+        for bin in first..=last {
+            let packets = bins.get(&bin).cloned().unwrap_or(0);
+            report.rows.push(vec![
+                (&name).into(),
+                milliseconds(bin * bin_ns),
+                milliseconds((bin + 1) * bin_ns),
+                packets.into(),
+                (packets as f64 / total as f64).into(),
+            ]);
+        }
+    }
+    Ok(report)
+}
+
+/// The latency statistics of the experiments named in `names` (all of them
+/// when empty) over windows of `window_ns` nanoseconds of send time,
+/// starting at the first packet sent
+pub fn latency_series(connection: &Connection, names: &[String], window_ns: i64) -> Result<Report> {
+    let mut report = Report::new(&[&["experiment", "window_start"][..], LATENCY_COLUMNS].concat());
+    let mut statement = connection.prepare(
+        r#"SELECT "send_ts_ns", "latency_ns" FROM "deliveries"
+           WHERE "experiment_id" = ?1 AND "delivered" AND "latency_ns" IS NOT NULL
+           AND "send_ts_ns" IS NOT NULL"#,
+    )?;
+    let mut start = connection
+        .prepare(r#"SELECT min("send_ts_ns") FROM "deliveries" WHERE "experiment_id" = ?"#)?;
+    for (id, name) in select_experiments(connection, names)? {
+        let start: i64 = match start.query_row([id], |row| row.get(0))? {
+            Some(start) => start,
+            None => continue,
+        };
+        let mut windows: BTreeMap<i64, Vec<i64>> = BTreeMap::new();
+        let mut rows = statement.query([id])?;
+        while let Some(row) = rows.next()? {
+            let (send, latency): (i64, i64) = (row.get(0)?, row.get(1)?);
+            windows
+                .entry((send - start) / window_ns)
+                .or_default()
+                .push(latency);
+        }
+        for (index, mut latencies) in windows {
+            if let Some(summary) = LatencySummary::new(&mut latencies) {
+                let window_start = format_timestamp(start + index * window_ns);
+                let mut row = vec![(&name).into(), (&window_start).into()];
+                row.extend(latency_values(&summary));
+                report.rows.push(row);
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// The parent churn of the nodes of the experiments named in `names` (all
+/// of them when empty), as stored in `churn_summary`
+pub fn churn(connection: &Connection, names: &[String]) -> Result<Report> {
+    let mut report = Report::new(&[
+        "experiment",
+        "node",
+        "changes",
+        "observed_s",
+        "changes_per_hour",
+    ]);
+    let mut statement = connection.prepare(
+        r#"SELECT "node", "changes", "observed_ns", "changes_per_hour" FROM "churn_summary"
+           WHERE "experiment_id" = ? ORDER BY "node""#,
+    )?;
+    for (id, name) in select_experiments(connection, names)? {
+        let rows = statement
+            .query_map([id], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, i64>(2)?,
+                    row.get::<_, Option<f64>>(3)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for (node, changes, observed, per_hour) in rows {
+            report.rows.push(vec![
+                (&name).into(),
+                (&node).into(),
+                changes.into(),
+                (observed as f64 / 1e9).into(),
+                per_hour.map_or(Value::Null, Value::from),
+            ]);
+        }
+    }
+    Ok(report)
+}
+
+/// The time spent by the nodes of the experiments named in `names` (all of
+/// them when empty) with each of their parents, as stored in
+/// `parent_durations`
+pub fn parent_durations(connection: &Connection, names: &[String]) -> Result<Report> {
+    let mut report = Report::new(&["experiment", "node", "parent", "duration_s", "share"]);
+    let mut statement = connection.prepare(
+        r#"SELECT "node", coalesce("parent", "parent_address"), "duration_ns", "share"
+           FROM "parent_durations" WHERE "experiment_id" = ?
+           ORDER BY "node", "duration_ns" DESC"#,
+    )?;
+    for (id, name) in select_experiments(connection, names)? {
+        let rows = statement
+            .query_map([id], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, Option<String>>(1)?,
+                    row.get::<_, i64>(2)?,
+                    row.get::<_, Option<f64>>(3)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for (node, parent, duration, share) in rows {
+            report.rows.push(vec![
+                (&name).into(),
+                (&node).into(),
+                parent.as_ref().map_or(Value::Null, Value::from),
+                (duration as f64 / 1e9).into(),
+                share.map_or(Value::Null, Value::from),
+            ]);
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::open_database;
+
+    const SECOND: i64 = 1_000_000_000;
+
+    #[test]
+    fn windows_without_packets_are_reported() {
+        let connection = open_database(":memory:").unwrap();
+        connection
+            .execute_batch(&format!(
+                r#"INSERT INTO "experiments" ("id", "Name") VALUES (1, 'run');
+                   INSERT INTO "deliveries" ("experiment_id", "node", "send_ts_ns", "delivered")
+                   VALUES (1, 'm3-1', 0, 1), (1, 'm3-2', {}, 0), (1, 'm3-1', {}, 1);"#,
+                SECOND / 2,
+                3 * SECOND
+            ))
+            .unwrap();
+        let report = pdr(&connection, &[], Some(SECOND)).unwrap();
+        let windows: Vec<&[Value]> = report
+            .rows
+            .iter()
+            .filter(|row| row[2] != Value::Null)
+            .map(|row| &row[3..])
+            .collect();
+        assert_eq!(
+            windows,
+            [
+                &[2.into(), 1.into(), 0.5.into()][..],
+                &[0.into(), 0.into(), Value::Null][..],
+                &[0.into(), 0.into(), Value::Null][..],
+                &[1.into(), 1.into(), 1.0.into()][..],
+            ]
+        );
+        /* The overall row and the two nodes come first */
+        assert_eq!(report.rows.len(), 3 + 4);
+        assert_eq!(
+            report.rows[0][3..],
+            [3.into(), 2.into(), (2.0 / 3.0).into()]
+        );
+    }
+
+    #[test]
+    fn histograms_keep_empty_bins_and_are_bounded() {
+        let connection = open_database(":memory:").unwrap();
+        connection
+            .execute_batch(
+                r#"INSERT INTO "experiments" ("id", "Name") VALUES (1, 'run');
+                   INSERT INTO "deliveries" ("experiment_id", "node", "latency_ns", "delivered")
+                   VALUES (1, 'm3-1', 1500000, 1), (1, 'm3-2', 1200000, 1),
+                          (1, 'm3-1', 3100000, 1), (1, 'm3-1', NULL, 0);"#,
+            )
+            .unwrap();
+        let report = latency_histogram(&connection, &[], 1_000_000).unwrap();
+        let bins: Vec<&[Value]> = report.rows.iter().map(|row| &row[1..4]).collect();
+        assert_eq!(
+            bins,
+            [
+                &[1.0.into(), 2.0.into(), 2.into()][..],
+                &[2.0.into(), 3.0.into(), 0.into()][..],
+                &[3.0.into(), 4.0.into(), 1.into()][..],
+            ]
+        );
+
+        /* 1.9 ms in bins of 100 ns would need 19 001 bins */
+        let error = latency_histogram(&connection, &[], 100).unwrap_err();
+        match error.kind {
+            ErrorKind::TooManyBins(width) => {
+                assert!(width > 100);
+                latency_histogram(&connection, &[], width).unwrap();
+            }
+            kind => panic!("{}", kind),
+        }
+    }
+}
@@ -0,0 +1,308 @@
+use crate::error::{Error, ErrorKind, Result};
+use crate::record::Value;
+use crate::sqlite::timestamp_index;
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use std::collections::HashSet;
+
+/// Tables which are not made of serial lines, or which are derived from them
+const RESERVED_TABLES: &[&str] = &[
+    "server",
+    "rejects",
+    "experiments",
+    "node_reboots",
+    "clock_fit",
+    "deliveries",
+    "latency_summary",
+    "topology_edges",
+    "parent_changes",
+    "churn_summary",
+    "parent_durations",
+    "stats_deltas",
+    "rpl_stats_deltas",
+    "neighbor_stats_deltas",
+];
+
+/// Columns added to every tag table
+pub(crate) const RESERVED_COLUMNS: &[&str] =
+    &["Timestamp", "Node", "experiment_id", "ts_ns", "boot"];
+
+lazy_static! {
+    static ref BUILTIN: Schema = Schema::parse(include_str!("../schema/default.toml"))
+        .expect("the built-in schema is valid");
+}
+
+/// The SQL type of a column, and the way a raw field is converted
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColumnType {
+    Integer,
+    Real,
+    Text,
+}
+
+impl ColumnType {
+    pub fn sql(&self) -> &'static str {
+        match self {
+            ColumnType::Integer => "INTEGER",
+            ColumnType::Real => "REAL",
+            ColumnType::Text => "TEXT",
+        }
+    }
+
+    /// Converts a raw field according to this parsing rule
+    pub fn parse(&self, raw: &str) -> Result<Value> {
+        match self {
+            ColumnType::Integer => Ok(Value::Integer(
+                raw.replace(' ', "")
+                    .parse::<i64>()
+                    .map_err(|e| Error::from(e).with_value(raw))?,
+            )),
+            ColumnType::Real => Ok(Value::Real(
+                raw.replace(' ', "")
+                    .parse::<f64>()
+                    .map_err(|e| Error::from(e).with_value(raw))?,
+            )),
+            ColumnType::Text => Ok(Value::from(raw)),
+        }
+    }
+}
+
+/// A column of the table of a tag
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ColumnSchema {
+    pub name: String,
+    pub header: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: ColumnType,
+    pub parse: Option<ColumnType>,
+}
+
+impl ColumnSchema {
+    /// The name of the field in the header printed by the nodes
+    pub fn header(&self) -> &str {
+        self.header.as_deref().unwrap_or(&self.name)
+    }
+
+    pub fn parse_rule(&self) -> ColumnType {
+        self.parse.unwrap_or(self.kind)
+    }
+}
+
+/// How the lines of a tag are stored
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TagSchema {
+    pub name: String,
+    pub table: Option<String>,
+    #[serde(rename = "column", default)]
+    pub columns: Vec<ColumnSchema>,
+}
+
+impl TagSchema {
+    pub fn table(&self) -> &str {
+        self.table.as_deref().unwrap_or(&self.name)
+    }
+
+    /// The header the nodes are expected to print for this tag
+    pub fn header(&self) -> Vec<String> {
+        self.columns
+            .iter()
+            .map(|c| c.header().to_string())
+            .collect()
+    }
+
+    /// Whether this tag is stored as in the built-in schema, in which case
+    /// its lines are parsed into the dedicated `SerialRecord` variant
+    pub fn is_builtin(&self) -> bool {
+        BUILTIN.tag(&self.name) == Some(self)
+    }
+
+    /// The `CREATE TABLE` statement of the table of this tag
+    pub fn create_statement(&self) -> String {
+        let mut columns = vec![
+            "  \"Timestamp\" TEXT".to_string(),
+            "  \"Node\" TEXT".to_string(),
+        ];
+        columns.extend(
+            self.columns
+                .iter()
+                .map(|c| format!("  \"{}\" {}", c.name.replace('"', "\"\""), c.kind.sql())),
+        );
+        columns.push("  \"experiment_id\" INTEGER".to_string());
+        columns.push("  \"ts_ns\" INTEGER".to_string());
+        columns.push("  \"boot\" INTEGER".to_string());
+        format!(
+            "CREATE TABLE IF NOT EXISTS \"{}\" (\n{}\n);\n{}\n",
+            self.table().replace('"', "\"\""),
+            columns.join(",\n"),
+            timestamp_index(self.table())
+        )
+    }
+}
+
+/// The tables created for the tags of the serial output, their columns,
+/// and how the fields are parsed
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Schema {
+    #[serde(rename = "tag", default)]
+    pub tags: Vec<TagSchema>,
+}
+
+impl Default for Schema {
+    fn default() -> Self {
+        BUILTIN.clone()
+    }
+}
+
+impl Schema {
+    /// The layout of the tables as shipped with moulinette
+    pub fn builtin() -> &'static Schema {
+        &BUILTIN
+    }
+
+    /// Parses and validates a TOML schema, without merging it
+    fn parse(content: &str) -> Result<Schema> {
+        let schema: Schema =
+            toml::from_str(content).map_err(|e| Error::new(ErrorKind::Schema(e.to_string())))?;
+        schema.validate()?;
+        Ok(schema)
+    }
+
+    /// Checks that tables and columns are not declared twice, and that the
+    /// tables do not clash with the server and rejects tables
+    fn validate(&self) -> Result<()> {
+        let invalid = |tag: &TagSchema, message: String| {
+            Err(Error::new(ErrorKind::Schema(message)).with_tag(&tag.name))
+        };
+        let mut tables: HashSet<&str> = RESERVED_TABLES.iter().copied().collect();
+        for tag in &self.tags {
+            if !tables.insert(tag.table()) {
+                return invalid(tag, format!("table {} is already used", tag.table()));
+            }
+            let mut columns: HashSet<&str> = RESERVED_COLUMNS.iter().copied().collect();
+            for column in &tag.columns {
+                if !columns.insert(&column.name) {
+                    return invalid(
+                        tag,
+                        format!("column {} is declared twice or reserved", column.name),
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Parses a TOML schema and merges it with the built-in one: its tags
+    /// replace the built-in tags of the same name
+    pub fn from_toml(content: &str) -> Result<Schema> {
+        let custom = Schema::parse(content)?;
+        let mut schema = Schema::builtin().clone();
+        for tag in custom.tags {
+            match schema.tags.iter_mut().find(|t| t.name == tag.name) {
+                Some(existing) => *existing = tag,
+                None => schema.tags.push(tag),
+            }
+        }
+        /* Custom tables may clash with built-in ones */
+        schema.validate()?;
+        Ok(schema)
+    }
+
+    pub fn from_file(path: &str) -> Result<Schema> {
+        let content = std::fs::read_to_string(path).map_err(|e| Error::from(e).in_file(path))?;
+        Schema::from_toml(&content).map_err(|e| e.in_file(path))
+    }
+
+    pub fn tag(&self, name: &str) -> Option<&TagSchema> {
+        self.tags.iter().find(|t| t.name == name)
+    }
+
+    /// The `CREATE TABLE` statements of the tables of all the tags
+    pub fn create_statements(&self) -> String {
+        self.tags
+            .iter()
+            .map(|t| t.create_statement())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags_replace_the_builtin_ones() {
+        let schema = Schema::from_toml(
+            r#"
+            [[tag]]
+            name = "neighbor_stats"
+            table = "neighbors"
+            column = [
+              { name = "address", header = "L2 address", type = "text" },
+              { name = "rssi", header = "rssi (dBm)", type = "real" },
+            ]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(schema.tags.len(), Schema::builtin().tags.len());
+        let tag = schema.tag("neighbor_stats").unwrap();
+        assert!(!tag.is_builtin());
+        assert_eq!(tag.table(), "neighbors");
+        assert_eq!(tag.header(), ["L2 address", "rssi (dBm)"]);
+        assert_eq!(
+            tag.columns[1].parse_rule().parse("-7 5").unwrap(),
+            Value::Real(-75.0)
+        );
+        assert!(schema.tag("stats").unwrap().is_builtin());
+    }
+
+    #[test]
+    fn tags_are_added() {
+        let schema = Schema::from_toml(
+            r#"
+            [[tag]]
+            name = "energy"
+            column = [
+              { name = "cpu", type = "integer" },
+              { name = "radio", type = "integer", parse = "text" },
+            ]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(schema.tags.len(), Schema::builtin().tags.len() + 1);
+        let tag = schema.tag("energy").unwrap();
+        assert_eq!(tag.table(), "energy");
+        assert_eq!(tag.columns[1].parse_rule(), ColumnType::Text);
+        let statements = schema.create_statements();
+        assert!(
+            statements.contains("CREATE TABLE IF NOT EXISTS \"energy\" (\n  \"Timestamp\" TEXT,")
+        );
+        assert!(statements.contains("  \"cpu\" INTEGER,\n  \"radio\" INTEGER"));
+    }
+
+    fn invalid(content: &str) -> String {
+        let error = Schema::from_toml(content).unwrap_err();
+        assert_eq!(error.kind.name(), "schema");
+        error.to_string()
+    }
+
+    #[test]
+    fn malformed_schemas_are_refused() {
+        invalid("[[tag]]\nname = \"energy\"\ncolumn = [ { name = \"cpu\" } ]");
+        invalid("[[tag]]\nname = \"energy\"\ncolumn = [ { name = \"cpu\", type = \"float\" } ]");
+        invalid("[[tag]]\nname = \"energy\"\nunknown = 1");
+        invalid("[[tag\nname = \"energy\"");
+        let message = invalid(
+            "[[tag]]\nname = \"energy\"\ncolumn = [ { name = \"Node\", type = \"text\" } ]",
+        );
+        assert!(message.contains("column Node is declared twice or reserved"));
+        let message = invalid("[[tag]]\nname = \"energy\"\ntable = \"server\"");
+        assert!(message.contains("table server is already used"));
+        let message = invalid("[[tag]]\nname = \"energy\"\ntable = \"stats\"");
+        assert!(message.contains("table stats is already used"));
+    }
+}
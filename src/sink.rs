@@ -0,0 +1,100 @@
+use crate::error::Result;
+use crate::record::{Experiment, Reject, SerialRecord, ServerRecord};
+
+/// A storage backend for the parsed records.
+///
+/// Records are written in batches: `begin` opens a batch, and `commit`
+/// makes every record written since then durable, while `rollback` drops
+/// them. The records written between `begin_experiment` and `end_experiment`
+/// belong to that experiment. `abort_experiment` drops the experiment and all
+/// its records, including the ones of the batches already committed.
+pub trait RecordSink {
+    fn begin_experiment(&mut self, experiment: &Experiment) -> Result<()>;
+    fn end_experiment(&mut self) -> Result<()>;
+    fn abort_experiment(&mut self) -> Result<()>;
+    fn begin(&mut self) -> Result<()>;
+    fn write_serial(&mut self, record: &SerialRecord) -> Result<()>;
+    fn write_server(&mut self, record: &ServerRecord) -> Result<()>;
+    fn write_reject(&mut self, reject: &Reject) -> Result<()>;
+    fn commit(&mut self) -> Result<()>;
+    fn rollback(&mut self) -> Result<()>;
+}
+
+/// Keeps the committed records in memory
+#[derive(Debug, Default)]
+pub struct MemorySink {
+    pub experiments: Vec<Experiment>,
+    pub serial: Vec<SerialRecord>,
+    pub server: Vec<ServerRecord>,
+    pub rejects: Vec<Reject>,
+    pending_serial: Vec<SerialRecord>,
+    pending_server: Vec<ServerRecord>,
+    pending_rejects: Vec<Reject>,
+    /* Number of committed records when the current experiment began */
+    marks: (usize, usize, usize),
+}
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl RecordSink for MemorySink {
+    fn begin_experiment(&mut self, experiment: &Experiment) -> Result<()> {
+        self.experiments.push(experiment.clone());
+        self.marks = (self.serial.len(), self.server.len(), self.rejects.len());
+        Ok(())
+    }
+
+    fn end_experiment(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn abort_experiment(&mut self) -> Result<()> {
+        self.experiments.pop();
+        self.serial.truncate(self.marks.0);
+        self.server.truncate(self.marks.1);
+        self.rejects.truncate(self.marks.2);
+        self.begin()
+    }
+
+    fn begin(&mut self) -> Result<()> {
+        self.pending_serial.clear();
+        self.pending_server.clear();
+        self.pending_rejects.clear();
+        Ok(())
+    }
+
+    fn write_serial(&mut self, record: &SerialRecord) -> Result<()> {
+        if let Some(experiment) = self.experiments.last_mut() {
+            experiment.include(*record.timestamp());
+        }
+        self.pending_serial.push(record.clone());
+        Ok(())
+    }
+
+    fn write_server(&mut self, record: &ServerRecord) -> Result<()> {
+        if let Some(experiment) = self.experiments.last_mut() {
+            experiment.include(record.timestamp);
+        }
+        self.pending_server.push(record.clone());
+        Ok(())
+    }
+
+    fn write_reject(&mut self, reject: &Reject) -> Result<()> {
+        self.pending_rejects.push(reject.clone());
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        self.serial.append(&mut self.pending_serial);
+        self.server.append(&mut self.pending_server);
+        self.rejects.append(&mut self.pending_rejects);
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<()> {
+        self.begin()
+    }
+}
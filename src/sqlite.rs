@@ -0,0 +1,522 @@
+use crate::error::{Error, ErrorKind, Result};
+use crate::migrations;
+use crate::record::{Experiment, Reject, SerialRecord, ServerRecord, Value};
+use crate::schema::Schema;
+use crate::sink::RecordSink;
+use chrono::{DateTime, Utc};
+use rusqlite::types::{ToSqlOutput, ValueRef};
+use rusqlite::{params, params_from_iter, Connection, ToSql};
+use std::collections::{HashMap, HashSet};
+
+impl ToSql for Value {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::Borrowed(match self {
+            Value::Null => ValueRef::Null,
+            Value::Integer(v) => ValueRef::Integer(*v),
+            Value::Real(v) => ValueRef::Real(*v),
+            Value::Text(v) => ValueRef::Text(v.as_bytes()),
+            Value::Blob(v) => ValueRef::Blob(v),
+        }))
+    }
+}
+
+fn setup_database(connection: &Connection, schema: &Schema) -> Result<()> {
+    /* Bring the database up to date */
+    migrations::migrate(connection)?;
+
+    /* Create the tables of the serial tags */
+    connection.execute_batch(&schema.create_statements())?;
+    Ok(())
+}
+
+/// Quotes a table or column name
+pub(crate) fn quote(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+/// The statement creating the index on the `ts_ns` column of `table`
+pub(crate) fn timestamp_index(table: &str) -> String {
+    format!(
+        "CREATE INDEX IF NOT EXISTS {} ON {} (\"ts_ns\");",
+        quote(&format!("{}_ts_ns", table)),
+        quote(table)
+    )
+}
+
+fn insert(
+    connection: &Connection,
+    table: &str,
+    columns: &[&str],
+    values: Vec<Value>,
+) -> Result<()> {
+    let columns: Vec<String> = columns.iter().map(|c| quote(c)).collect();
+    let placeholders = vec!["?"; columns.len()].join(", ");
+    let mut stmt = connection.prepare_cached(&format!(
+        "INSERT INTO {} ({}) VALUES ({})",
+        quote(table),
+        columns.join(", "),
+        placeholders
+    ))?;
+    match stmt.execute(params_from_iter(values))? {
+        1 => Ok(()),
+        n => Err(Error::new(ErrorKind::Insert(n)).with_tag(table)),
+    }
+}
+
+/// Number of rows of a new tag used to infer the types of its columns
+const INFERENCE_ROWS: usize = 100;
+
+/// The column type able to hold both `current` and `value`
+fn widen(current: Option<&'static str>, value: &Value) -> Option<&'static str> {
+    match (current, value) {
+        (current, Value::Null) => current,
+        (None, Value::Integer(_)) => Some("INTEGER"),
+        (Some("INTEGER"), Value::Real(_)) | (None, Value::Real(_)) => Some("REAL"),
+        (_, Value::Text(_)) => Some("TEXT"),
+        (None, Value::Blob(_)) => Some("BLOB"),
+        (current, _) => current,
+    }
+}
+
+/// A row of a generic record: column names and values
+type Row = (Vec<String>, Vec<Value>);
+
+/// Writes the records into a SQLite database, one transaction per batch.
+///
+/// Records of tags without a dedicated table get their own table, created
+/// from their header once the types of the columns have been inferred from
+/// their first rows.
+pub struct SqliteSink {
+    connection: Connection,
+
+    /* Columns of each table of the database */
+    tables: HashMap<String, HashSet<String>>,
+
+    /* Rows of the tables which do not exist yet */
+    pending: HashMap<String, Vec<Row>>,
+
+    /* The current experiment and its row id */
+    experiment: Option<(i64, Experiment)>,
+}
+
+impl SqliteSink {
+    /// Opens the database, creating the tables of `schema` if needed
+    pub fn open(path: &str, schema: &Schema) -> Result<Self> {
+        let connection = Connection::open(path)?;
+        setup_database(&connection, schema)?;
+        let mut sink = SqliteSink {
+            connection,
+            tables: HashMap::new(),
+            pending: HashMap::new(),
+            experiment: None,
+        };
+        sink.load_tables()?;
+        Ok(sink)
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    pub fn vacuum(&self) -> Result<()> {
+        self.connection.execute_batch(
+            r#"VACUUM;
+        "#,
+        )?;
+        Ok(())
+    }
+
+    /// Replaces the current experiment and its row id, so that a single sink
+    /// can write the batches of several experiments in turn
+    pub(crate) fn swap_experiment(
+        &mut self,
+        experiment: Option<(i64, Experiment)>,
+    ) -> Option<(i64, Experiment)> {
+        std::mem::replace(&mut self.experiment, experiment)
+    }
+
+    fn load_tables(&mut self) -> Result<()> {
+        let names: Vec<String> = self
+            .connection
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table'")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        for name in names {
+            let columns = self
+                .connection
+                .prepare(&format!("PRAGMA table_info({})", quote(&name)))?
+                .query_map([], |row| row.get(1))?
+                .collect::<rusqlite::Result<_>>()?;
+            self.tables.insert(name, columns);
+        }
+        Ok(())
+    }
+
+    /// Creates `table` with the columns of `rows`, and inserts them
+    fn create_table(&mut self, table: &str, rows: Vec<Row>) -> Result<()> {
+        let mut columns: Vec<(String, Option<&'static str>)> = Vec::new();
+        for (names, values) in &rows {
+            for (name, value) in names.iter().zip(values) {
+                match columns.iter_mut().find(|(c, _)| c == name) {
+                    Some((_, kind)) => *kind = widen(*kind, value),
+                    None => columns.push((name.clone(), widen(None, value))),
+                }
+            }
+        }
+        let definitions: Vec<String> = columns
+            .iter()
+            .map(|(name, kind)| format!("{} {}", quote(name), kind.unwrap_or("TEXT")))
+            .collect();
+        println!("Creating table {} ({})", table, definitions.join(", "));
+        self.connection.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS {} ({});",
+            quote(table),
+            definitions.join(", ")
+        ))?;
+        if columns.iter().any(|(name, _)| name == "ts_ns") {
+            self.connection.execute_batch(&timestamp_index(table))?;
+        }
+        self.tables.insert(
+            table.to_string(),
+            columns.into_iter().map(|(name, _)| name).collect(),
+        );
+        for (names, values) in rows {
+            self.insert_row(table, names, values)?;
+        }
+        Ok(())
+    }
+
+    /// Inserts a row into an existing table, adding the columns it lacks
+    fn insert_row(&mut self, table: &str, names: Vec<String>, values: Vec<Value>) -> Result<()> {
+        let known = self.tables.entry(table.to_string()).or_default();
+        for (name, value) in names.iter().zip(&values) {
+            if !known.contains(name) {
+                let kind = widen(None, value).unwrap_or("TEXT");
+                println!("Adding column {} {} to table {}", name, kind, table);
+                self.connection.execute_batch(&format!(
+                    "ALTER TABLE {} ADD COLUMN {} {};",
+                    quote(table),
+                    quote(name),
+                    kind
+                ))?;
+                known.insert(name.clone());
+            }
+        }
+        let names: Vec<&str> = names.iter().map(|n| n.as_str()).collect();
+        insert(&self.connection, table, &names, values)
+    }
+
+    /// Creates the tables of the pending rows
+    fn flush_pending(&mut self) -> Result<()> {
+        let pending: Vec<(String, Vec<Row>)> = self.pending.drain().collect();
+        for (table, rows) in pending {
+            self.create_table(&table, rows)?;
+        }
+        Ok(())
+    }
+
+    /// The id of the current experiment, and extends its time span to `timestamp`
+    fn experiment_id(&mut self, timestamp: Option<DateTime<Utc>>) -> Value {
+        match (&mut self.experiment, timestamp) {
+            (Some((id, experiment)), Some(timestamp)) => {
+                experiment.include(timestamp);
+                Value::Integer(*id)
+            }
+            (Some((id, _)), None) => Value::Integer(*id),
+            (None, _) => Value::Null,
+        }
+    }
+
+    /// Inserts a record, tagged with the current experiment
+    fn insert(
+        &mut self,
+        table: &str,
+        mut columns: Vec<&str>,
+        mut values: Vec<Value>,
+        timestamp: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        columns.push("experiment_id");
+        values.push(self.experiment_id(timestamp));
+        insert(&self.connection, table, &columns, values)
+    }
+
+    fn write_generic(&mut self, record: &SerialRecord) -> Result<()> {
+        let table = record.table().to_string();
+        let mut names: Vec<String> = record.columns().iter().map(|c| c.to_string()).collect();
+        let mut values = record.values();
+        names.push("experiment_id".to_string());
+        values.push(self.experiment_id(Some(*record.timestamp())));
+        if self.tables.contains_key(&table) {
+            return self.insert_row(&table, names, values);
+        }
+        let rows = self.pending.entry(table.clone()).or_default();
+        rows.push((names, values));
+        if rows.len() >= INFERENCE_ROWS {
+            let rows = self.pending.remove(&table).unwrap_or_default();
+            self.create_table(&table, rows)?;
+        }
+        Ok(())
+    }
+}
+
+impl RecordSink for SqliteSink {
+    fn begin_experiment(&mut self, experiment: &Experiment) -> Result<()> {
+        let exists: i64 = self.connection.query_row(
+            "SELECT count(*) FROM experiments WHERE Name = ?",
+            [&experiment.name],
+            |row| row.get(0),
+        )?;
+        if exists > 0 {
+            return Err(Error::new(ErrorKind::ExperimentExists(
+                experiment.name.clone(),
+            )));
+        }
+        self.connection.execute(
+            r#"INSERT INTO experiments ("Name", "Serial File", "Server File", "Parameters")
+               VALUES (?, ?, ?, ?)"#,
+            [
+                &experiment.name,
+                &experiment.serial_file,
+                &experiment.server_file,
+                &experiment.parameters_json(),
+            ],
+        )?;
+        let id = self.connection.last_insert_rowid();
+        self.experiment = Some((id, experiment.clone()));
+        Ok(())
+    }
+
+    fn end_experiment(&mut self) -> Result<()> {
+        if let Some((id, experiment)) = self.experiment.take() {
+            let timestamp =
+                |t: Option<DateTime<Utc>>| t.map_or(Value::Null, |t| Value::from(&t.to_string()));
+            self.connection.execute(
+                r#"UPDATE experiments SET "Start" = ?, "End" = ? WHERE id = ?"#,
+                params![timestamp(experiment.start), timestamp(experiment.end), id],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn abort_experiment(&mut self) -> Result<()> {
+        let id = match self.experiment.take() {
+            Some((id, _)) => id,
+            None => return Ok(()),
+        };
+        if !self.connection.is_autocommit() {
+            /* Drop the batch interrupted by the failure */
+            self.rollback()?;
+        }
+        let mut tables: Vec<&String> = self
+            .tables
+            .iter()
+            .filter(|(_, columns)| columns.contains("experiment_id"))
+            .map(|(table, _)| table)
+            .collect();
+        tables.sort();
+        let mut statements = String::from("BEGIN;\n");
+        for table in tables {
+            statements += &format!(
+                "DELETE FROM {} WHERE \"experiment_id\" = {};\n",
+                quote(table),
+                id
+            );
+        }
+        statements += &format!("DELETE FROM \"experiments\" WHERE id = {};\nCOMMIT;", id);
+        self.connection.execute_batch(&statements)?;
+        Ok(())
+    }
+
+    fn begin(&mut self) -> Result<()> {
+        self.connection.execute_batch("BEGIN")?;
+        Ok(())
+    }
+
+    fn write_serial(&mut self, record: &SerialRecord) -> Result<()> {
+        match record {
+            SerialRecord::Generic(_) => self.write_generic(record),
+            _ => self.insert(
+                record.table(),
+                record.columns(),
+                record.values(),
+                Some(*record.timestamp()),
+            ),
+        }
+        .map_err(|e| e.with_node(record.node()))
+    }
+
+    fn write_server(&mut self, record: &ServerRecord) -> Result<()> {
+        self.insert(
+            "server",
+            record.columns(),
+            record.values(),
+            Some(record.timestamp),
+        )
+    }
+
+    fn write_reject(&mut self, reject: &Reject) -> Result<()> {
+        self.insert("rejects", reject.columns(), reject.values(), None)
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        self.flush_pending()?;
+        self.connection.execute_batch("COMMIT")?;
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<()> {
+        self.connection.execute_batch("ROLLBACK")?;
+        /* Tables created during the batch are gone as well */
+        self.pending.clear();
+        self.tables.clear();
+        self.load_tables()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::{Experiment, Generic};
+    use chrono::{TimeZone, Utc};
+
+    fn generic(tag: &str, header: &[&str], values: Vec<Value>) -> SerialRecord {
+        SerialRecord::Generic(Generic {
+            timestamp: Utc.timestamp(1_600_000_000, 0),
+            node: "m3-1".to_string(),
+            tag: tag.to_string(),
+            table: tag.to_string(),
+            header: header.iter().map(|h| h.to_string()).collect(),
+            values,
+        })
+    }
+
+    fn rows(sink: &SqliteSink, query: &str) -> Vec<(String, String)> {
+        sink.connection()
+            .prepare(query)
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn text_after_the_inferred_rows_is_kept() {
+        let mut sink = SqliteSink::open(":memory:", &Schema::default()).unwrap();
+        sink.begin().unwrap();
+        for i in 0..INFERENCE_ROWS as i64 {
+            let record = generic("foo", &["count"], vec![Value::Integer(i)]);
+            sink.write_serial(&record).unwrap();
+        }
+        let record = generic("foo", &["count"], vec![Value::from("n/a")]);
+        sink.write_serial(&record).unwrap();
+        sink.commit().unwrap();
+
+        let columns = rows(&sink, r#"SELECT name, type FROM pragma_table_info('foo')"#);
+        assert!(columns.contains(&("count".to_string(), "INTEGER".to_string())));
+        let last = rows(
+            &sink,
+            r#"SELECT typeof("count"), "count" FROM "foo" WHERE rowid = (SELECT max(rowid) FROM "foo")"#,
+        );
+        assert_eq!(last, [("text".to_string(), "n/a".to_string())]);
+        let count: i64 = sink
+            .connection()
+            .query_row(r#"SELECT count(*) FROM "foo""#, [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, INFERENCE_ROWS as i64 + 1);
+    }
+
+    #[test]
+    fn new_tags_get_a_table_after_the_first_flush() {
+        let mut sink = SqliteSink::open(":memory:", &Schema::default()).unwrap();
+        sink.begin().unwrap();
+        let record = generic("foo", &["a"], vec![Value::Integer(1)]);
+        sink.write_serial(&record).unwrap();
+        sink.commit().unwrap();
+
+        sink.begin().unwrap();
+        let record = generic("bar", &["b"], vec![Value::from("x")]);
+        sink.write_serial(&record).unwrap();
+        let record = generic(
+            "foo",
+            &["a", "c"],
+            vec![Value::Integer(2), Value::Real(0.5)],
+        );
+        sink.write_serial(&record).unwrap();
+        sink.commit().unwrap();
+
+        let bar = rows(&sink, r#"SELECT "Node", "b" FROM "bar""#);
+        assert_eq!(bar, [("m3-1".to_string(), "x".to_string())]);
+        let foo = rows(
+            &sink,
+            r#"SELECT CAST("a" AS TEXT), coalesce(CAST("c" AS TEXT), 'null') FROM "foo" ORDER BY "a""#,
+        );
+        assert_eq!(
+            foo,
+            [
+                ("1".to_string(), "null".to_string()),
+                ("2".to_string(), "0.5".to_string())
+            ]
+        );
+    }
+
+    fn server(port: i64) -> ServerRecord {
+        ServerRecord {
+            timestamp: Utc.timestamp(1_600_000_000, 0),
+            ipv6_address: "2001:db8::1".to_string(),
+            port,
+            payload: "abc".to_string(),
+        }
+    }
+
+    /// Writes a committed batch and an interrupted one for `name`
+    fn write_experiment(sink: &mut SqliteSink, name: &str, commit: bool) {
+        sink.begin_experiment(&Experiment::new(name, "serial.zst", "server.zst"))
+            .unwrap();
+        for batch in 0..2 {
+            sink.begin().unwrap();
+            let record = generic("foo", &["a"], vec![Value::Integer(batch)]);
+            sink.write_serial(&record).unwrap();
+            /* bar only gets a table at the end of the batch */
+            let record = generic("bar", &["b"], vec![Value::Integer(batch)]);
+            sink.write_serial(&record).unwrap();
+            sink.write_server(&server(batch)).unwrap();
+            if batch == 0 || commit {
+                sink.commit().unwrap();
+            }
+        }
+        if commit {
+            sink.end_experiment().unwrap();
+        }
+    }
+
+    fn count(sink: &SqliteSink, query: &str) -> i64 {
+        sink.connection()
+            .query_row(query, [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn aborted_experiments_are_dropped() {
+        let mut sink = SqliteSink::open(":memory:", &Schema::default()).unwrap();
+        write_experiment(&mut sink, "kept", true);
+        write_experiment(&mut sink, "failed", false);
+        sink.abort_experiment().unwrap();
+
+        let names = rows(&sink, r#"SELECT "Name", CAST(id AS TEXT) FROM experiments"#);
+        assert_eq!(names, [("kept".to_string(), "1".to_string())]);
+        for table in &["foo", "bar", "server"] {
+            let query = format!(
+                r#"SELECT count(*) FROM "{}" WHERE experiment_id IS NOT 1"#,
+                table
+            );
+            assert_eq!(count(&sink, &query), 0, "rows left in {}", table);
+            let query = format!(r#"SELECT count(*) FROM "{}""#, table);
+            assert_eq!(count(&sink, &query), 2, "rows lost in {}", table);
+        }
+
+        /* The experiment can be ingested again */
+        write_experiment(&mut sink, "failed", true);
+        assert_eq!(count(&sink, r#"SELECT count(*) FROM "foo""#), 4);
+    }
+}
@@ -0,0 +1,301 @@
+use crate::analysis::{table_exists, value_text};
+use crate::error::Result;
+use rusqlite::types::Value;
+use rusqlite::{params, Connection};
+use std::collections::HashMap;
+use std::net::Ipv6Addr;
+
+/// The interface identifier of an IPv6 address, i.e. its last 64 bits.
+///
+/// A node uses the same identifier in its link-local and global addresses,
+/// which is how the parents, known by their link-local address, are told
+/// apart.
+pub fn interface_identifier(address: &str) -> Option<u64> {
+    let address: Ipv6Addr = address.trim().parse().ok()?;
+    Some(u128::from(address) as u64)
+}
+
+/// The interface identifier derived from a link-layer address, as written in
+/// `neighbor_stats`: the modified EUI-64 of a long address (with the
+/// universal/local bit flipped), or `0000:00ff:fe00:XXXX` for a 16-bit short
+/// address.
+pub fn link_layer_identifier(address: &str) -> Option<u64> {
+    let bytes = address
+        .trim()
+        .split(':')
+        .map(|byte| u8::from_str_radix(byte, 16))
+        .collect::<std::result::Result<Vec<u8>, _>>()
+        .ok()?;
+    match bytes.as_slice() {
+        [high, low] => Some(0x0000_00ff_fe00_0000 | u64::from(*high) << 8 | u64::from(*low)),
+        [first, rest @ ..] if rest.len() == 7 => {
+            Some(rest.iter().fold(u64::from(first ^ 0x02), |iid, byte| {
+                iid << 8 | u64::from(*byte)
+            }))
+        }
+        _ => None,
+    }
+}
+
+/// The nodes of the experiment by interface identifier, learned from the
+/// source address of the packets they delivered to the server, and from the
+/// DODAG id advertised by the roots
+pub(crate) fn node_identifiers(
+    connection: &Connection,
+    experiment_id: i64,
+) -> Result<HashMap<u64, String>> {
+    let mut query = String::from(
+        r#"SELECT DISTINCT "node", "source_address" FROM "deliveries"
+           WHERE "experiment_id" = ?1 AND "source_address" IS NOT NULL"#,
+    );
+    if table_exists(connection, "rpl_stats_dodag")? {
+        query.push_str(
+            r#" UNION SELECT DISTINCT "Node", "IPv6 Adress" FROM "rpl_stats_dodag"
+                WHERE "experiment_id" = ?1 AND lower("Role") = 'root'"#,
+        );
+    }
+    let addresses = connection
+        .prepare(&query)?
+        .query_map([experiment_id], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut nodes: HashMap<u64, String> = HashMap::new();
+    for (node, address) in addresses {
+        if let Some(iid) = interface_identifier(&address) {
+            match nodes.get(&iid) {
+                Some(known) if *known != node => println!(
+                    "Warning: nodes {} and {} share the interface identifier of {}",
+                    known, node, address
+                ),
+                Some(_) => {}
+                None => {
+                    nodes.insert(iid, node);
+                }
+            }
+        }
+    }
+    Ok(nodes)
+}
+
+/// A run of consecutive samples of a node and instance with the same parent
+#[derive(Debug, Clone)]
+pub(crate) struct ParentRun {
+    pub child: String,
+    pub instance: Option<String>,
+    pub address: Option<String>,
+    /// The name of the parent, when its interface identifier is known
+    pub parent: Option<String>,
+    pub from: i64,
+    /// The time of the sample showing the next parent, or `None` for the last
+    /// run of the node and instance, whose parent is still the current one
+    pub to: Option<i64>,
+    /// The time of the last sample of the run
+    pub last: i64,
+    pub samples: i64,
+    /// The rank of the parent in the first and last samples of the run
+    pub first_rank: Option<String>,
+    pub last_rank: Option<String>,
+}
+
+/// The runs of samples of `rpl_stats_parent` with the same parent, ordered
+/// by node, instance and time, or `None` when there is no such table
+pub(crate) fn parent_runs(
+    connection: &Connection,
+    experiment_id: i64,
+) -> Result<Option<Vec<ParentRun>>> {
+    if !table_exists(connection, "rpl_stats_parent")? {
+        return Ok(None);
+    }
+    let nodes = node_identifiers(connection, experiment_id)?;
+
+    let mut statement = connection.prepare(
+        r#"SELECT "Node", "Instance ID", "IPv6 Adress", "Rank", "ts_ns" FROM "rpl_stats_parent"
+           WHERE "experiment_id" = ? AND "ts_ns" IS NOT NULL
+           ORDER BY "Node", "Instance ID", "ts_ns""#,
+    )?;
+    let mut rows = statement.query([experiment_id])?;
+    let mut runs: Vec<ParentRun> = Vec::new();
+    while let Some(row) = rows.next()? {
+        let (child, instance, address): (String, Option<String>, Option<String>) =
+            (row.get(0)?, row.get(1)?, row.get(2)?);
+        let rank = row.get::<_, Option<Value>>(3)?.map(value_text);
+        let ts_ns: i64 = row.get(4)?;
+        match runs.last_mut() {
+            Some(run) if run.child == child && run.instance == instance => {
+                if run.address == address {
+                    run.samples += 1;
+                    run.last = ts_ns;
+                    run.last_rank = rank;
+                    continue;
+                }
+                run.to = Some(ts_ns);
+            }
+            _ => {}
+        }
+        let parent = address
+            .as_deref()
+            .and_then(interface_identifier)
+            .and_then(|iid| nodes.get(&iid))
+            .cloned();
+        runs.push(ParentRun {
+            child,
+            instance,
+            address,
+            parent,
+            from: ts_ns,
+            to: None,
+            last: ts_ns,
+            samples: 1,
+            first_rank: rank.clone(),
+            last_rank: rank,
+        });
+    }
+    Ok(Some(runs))
+}
+
+/// Writes the RPL parent of each node over time to `topology_edges`, from
+/// the samples of `rpl_stats_parent`.
+///
+/// Each row covers the consecutive samples of a node and instance with the
+/// same parent address: it is valid from the first of them up to the sample
+/// where another parent was seen. The last parent of a node has no end: it is
+/// valid until the end of the experiment. The parent is named when its
+/// interface identifier is known.
+pub fn build_topology(connection: &Connection, experiment_id: i64) -> Result<()> {
+    connection.execute(
+        r#"DELETE FROM "topology_edges" WHERE "experiment_id" = ?"#,
+        [experiment_id],
+    )?;
+    let runs = match parent_runs(connection, experiment_id)? {
+        Some(runs) => runs,
+        None => {
+            println!("Warning: no rpl_stats_parent table, the topology cannot be built");
+            return Ok(());
+        }
+    };
+
+    let mut insert = connection.prepare(
+        r#"INSERT INTO "topology_edges"
+           ("experiment_id", "child", "parent", "parent_address", "instance",
+            "valid_from", "valid_to", "samples")
+           VALUES (?, ?, ?, ?, ?, ?, ?, ?)"#,
+    )?;
+    for run in &runs {
+        insert.execute(params![
+            experiment_id,
+            run.child,
+            run.parent,
+            run.address,
+            run.instance,
+            run.from,
+            run.to,
+            run.samples
+        ])?;
+    }
+    println!(
+        "{} parent intervals, {} with an unknown parent",
+        runs.len(),
+        runs.iter().filter(|run| run.parent.is_none()).count()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::open_database;
+
+    /// The child, parent, validity and samples of an edge
+    type EdgeRow = (String, Option<String>, i64, Option<i64>, i64);
+
+    #[test]
+    fn identifiers_are_the_last_64_bits() {
+        assert_eq!(
+            interface_identifier("fe80::28ab:dc15:7f00:1"),
+            Some(0x28ab_dc15_7f00_0001)
+        );
+        assert_eq!(
+            interface_identifier(" 2001:db8::28ab:dc15:7f00:1 "),
+            interface_identifier("fe80::28ab:dc15:7f00:1")
+        );
+        assert_eq!(interface_identifier("m3-1"), None);
+        assert_eq!(interface_identifier(""), None);
+    }
+
+    #[test]
+    fn link_layer_identifiers() {
+        /* Long addresses: modified EUI-64, with the universal/local bit flipped */
+        assert_eq!(
+            link_layer_identifier("2A:AB:DC:15:7F:00:00:01"),
+            Some(0x28ab_dc15_7f00_0001)
+        );
+        assert_eq!(
+            link_layer_identifier("28:ab:dc:15:7f:00:00:01"),
+            Some(0x2aab_dc15_7f00_0001)
+        );
+        /* Short addresses */
+        assert_eq!(link_layer_identifier("12:34"), Some(0x0000_00ff_fe00_1234));
+        assert_eq!(link_layer_identifier(" 0:1 "), Some(0x0000_00ff_fe00_0001));
+        for invalid in &["", "12", "12:34:56", "12:345", "zz:00", "1:2:3:4:5:6:7:8:9"] {
+            assert_eq!(link_layer_identifier(invalid), None, "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn identifiers_match_the_addresses() {
+        let iid = interface_identifier("fe80::28ab:dc15:7f00:1");
+        assert_eq!(iid, Some(0x28ab_dc15_7f00_0001));
+        assert_eq!(iid, link_layer_identifier("2a:ab:dc:15:7f:00:00:01"));
+        assert_eq!(
+            interface_identifier("2001:db8::ff:fe00:1234"),
+            link_layer_identifier("12:34")
+        );
+        assert_eq!(interface_identifier("m3-1"), None);
+    }
+
+    #[test]
+    fn the_last_parent_has_no_end() {
+        let connection = open_database(":memory:").unwrap();
+        connection
+            .execute_batch(
+                r#"CREATE TABLE "rpl_stats_parent" (
+                     "experiment_id" INTEGER, "Node" TEXT, "Instance ID" TEXT,
+                     "IPv6 Adress" TEXT, "ts_ns" INTEGER);
+                   INSERT INTO "rpl_stats_parent" VALUES
+                     (1, 'm3-2', '0', 'fe80::1', 10), (1, 'm3-2', '0', 'fe80::1', 20),
+                     (1, 'm3-2', '0', 'fe80::3', 30), (1, 'm3-3', '0', 'fe80::2', 15);
+                   INSERT INTO "deliveries" ("experiment_id", "node", "source_address")
+                   VALUES (1, 'm3-1', '2001:db8::1');"#,
+            )
+            .unwrap();
+        build_topology(&connection, 1).unwrap();
+        let edges: Vec<EdgeRow> = connection
+            .prepare(
+                r#"SELECT "child", "parent", "valid_from", "valid_to", "samples"
+                   FROM "topology_edges" ORDER BY "child", "valid_from""#,
+            )
+            .unwrap()
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            edges,
+            [
+                ("m3-2".into(), Some("m3-1".into()), 10, Some(30), 2),
+                ("m3-2".into(), None, 30, None, 1),
+                ("m3-3".into(), None, 15, None, 1),
+            ]
+        );
+    }
+}